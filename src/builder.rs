@@ -0,0 +1,67 @@
+use crate::engine::{choose, Engine, Matcher};
+use crate::lex::Flags;
+use crate::{CompileError, KyomuRegex, LazyDfa, Nfa};
+
+// Compiles a pattern with options that are not written in the pattern itself
+pub struct KyomuRegexBuilder {
+    pattern: String,
+    flags: Flags,
+    engine: Engine,
+}
+
+impl KyomuRegexBuilder {
+    pub fn new(pattern: &str) -> Self {
+        KyomuRegexBuilder {
+            pattern: pattern.to_string(),
+            flags: Flags::default(),
+            engine: Engine::Auto,
+        }
+    }
+
+    // same as starting the pattern with (?i)
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+        self.flags.case_insensitive = yes;
+        self
+    }
+
+    // Same as starting the pattern with (?s). Off by default, so . does not match \n.
+    pub fn dot_matches_new_line(&mut self, yes: bool) -> &mut Self {
+        self.flags.dot_matches_new_line = yes;
+        self
+    }
+
+    // same as starting the pattern with (?x)
+    pub fn verbose(&mut self, yes: bool) -> &mut Self {
+        self.flags.verbose = yes;
+        self
+    }
+
+    // the engine `build_matcher` uses
+    pub fn engine(&mut self, engine: Engine) -> &mut Self {
+        self.engine = engine;
+        self
+    }
+
+    pub fn build(&self) -> Result<KyomuRegex, CompileError> {
+        KyomuRegex::compile_with_flags(&self.pattern, self.flags)
+    }
+
+    // Compiles for the chosen engine. Engine::Nfa fails on patterns with & ~ or lookarounds.
+    // The matcher can be sent to another thread but not shared, see `Matcher`.
+    pub fn build_matcher(&self) -> Result<Box<dyn Matcher + Send>, CompileError> {
+        let regex = self.build()?;
+        let engine = match self.engine {
+            Engine::Auto => choose(&regex),
+            engine => engine,
+        };
+        Ok(match engine {
+            Engine::Auto => unreachable!("chosen above"),
+            Engine::Derivative => Box::new(regex),
+            Engine::LazyDfa => Box::new(LazyDfa::new(&regex)),
+            // a regex has no spans left, so the whole pattern is to blame
+            Engine::Nfa => Box::new(Nfa::new(&regex).map_err(|_| CompileError::UnsupportedByNfa {
+                span: 0..self.pattern.len(),
+            })?),
+        })
+    }
+}
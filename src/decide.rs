@@ -0,0 +1,189 @@
+// Decision procedures on the languages of regexes, by exploring derivatives
+use crate::dfa::classes;
+use crate::KyomuRegex;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+
+// a character from each class that tells apart the derivatives of `regexes`, in order
+pub(crate) fn representatives(regexes: &[&KyomuRegex]) -> Vec<char> {
+    let mut literals = BTreeSet::new();
+    regexes.iter().for_each(|r| r.literals(&mut literals));
+    let literals: Vec<char> = literals.into_iter().collect();
+    let mut chars: Vec<char> = classes(&literals).iter().map(|c| c.representative()).collect();
+    chars.sort();
+    chars
+}
+
+// Union-find over regexes, numbered as they are first seen
+#[derive(Default)]
+struct UnionFind {
+    ids: HashMap<KyomuRegex, usize>,
+    parent: Vec<usize>,
+}
+
+impl UnionFind {
+    fn find(&mut self, r: &KyomuRegex) -> usize {
+        let len = self.parent.len();
+        let mut id = *self.ids.entry(r.clone()).or_insert(len);
+        if id == len {
+            self.parent.push(id);
+        }
+        while self.parent[id] != id {
+            self.parent[id] = self.parent[self.parent[id]];
+            id = self.parent[id];
+        }
+        id
+    }
+    // false if `r` and `s` were already together
+    fn union(&mut self, r: &KyomuRegex, s: &KyomuRegex) -> bool {
+        let (r, s) = (self.find(r), self.find(s));
+        self.parent[r] = s;
+        r != s
+    }
+}
+
+impl KyomuRegex {
+    // Whether `self` and `other` match the same strings. If not, a shortest
+    // string matched by only one of them is returned.
+    // Pairs of derivatives are explored breadth-first (Hopcroft and Karp); a pair
+    // already known to be together in the union-find needs no second visit.
+    pub fn equivalent(&self, other: &KyomuRegex) -> Result<(), String> {
+        let chars = representatives(&[self, other]);
+        let mut classes = UnionFind::default();
+        classes.union(self, other);
+        let mut queue = VecDeque::from([(self.clone(), other.clone(), String::new())]);
+        while let Some((r, s, word)) = queue.pop_front() {
+            if r.match_eps() != s.match_eps() {
+                return Err(word);
+            }
+            for &ch in &chars {
+                let (r, s) = (r.derivative(ch), s.derivative(ch));
+                if classes.union(&r, &s) {
+                    let mut word = word.clone();
+                    word.push(ch);
+                    queue.push_back((r, s, word));
+                }
+            }
+        }
+        Ok(())
+    }
+    // Whether every string matched by `self` is matched by `other`. If not, a
+    // shortest string matched by `self` alone is returned.
+    pub fn is_subset_of(&self, other: &KyomuRegex) -> Result<(), String> {
+        let chars = representatives(&[self, other]);
+        let mut seen = HashSet::from([(self.clone(), other.clone())]);
+        let mut queue = VecDeque::from([(self.clone(), other.clone(), String::new())]);
+        while let Some((r, s, word)) = queue.pop_front() {
+            if r.match_eps() && !s.match_eps() {
+                return Err(word);
+            }
+            for &ch in &chars {
+                let pair = (r.derivative(ch), s.derivative(ch));
+                // nothing left to match on the left
+                if pair.0 == KyomuRegex::Empty || seen.contains(&pair) {
+                    continue;
+                }
+                seen.insert(pair.clone());
+                let mut word = word.clone();
+                word.push(ch);
+                queue.push_back((pair.0, pair.1, word));
+            }
+        }
+        Ok(())
+    }
+    // Whether no string matches
+    pub fn is_empty(&self) -> bool {
+        self.example().is_none()
+    }
+    // Whether every string matches
+    pub fn is_universal(&self) -> bool {
+        KyomuRegex::Not(Box::new(self.clone())).is_empty()
+    }
+    // The shortest matching string, and the smallest one among those. The
+    // characters not written in the pattern all lead to the same derivative,
+    // so only the smallest of them is tried: `c.` gives "c\0".
+    pub fn example(&self) -> Option<String> {
+        let mut literals = BTreeSet::new();
+        self.literals(&mut literals);
+        let other = (0..=char::MAX as u32)
+            .filter_map(char::from_u32)
+            .find(|c| !literals.contains(c));
+        // in order, so the queue holds words shortest first, then smallest
+        let chars: BTreeSet<char> = literals.iter().copied().chain(other).collect();
+        let mut seen = HashSet::from([self.clone()]);
+        let mut queue = VecDeque::from([(self.clone(), String::new())]);
+        while let Some((r, word)) = queue.pop_front() {
+            if r.match_eps() {
+                return Some(word);
+            }
+            for &ch in &chars {
+                let next = r.derivative(ch);
+                if next != KyomuRegex::Empty && seen.insert(next.clone()) {
+                    let mut word = word.clone();
+                    word.push(ch);
+                    queue.push_back((next, word));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::KyomuRegex;
+
+    fn equivalent(left: &str, right: &str) -> Result<(), String> {
+        KyomuRegex::compile(left).unwrap().equivalent(&KyomuRegex::compile(right).unwrap())
+    }
+
+    #[test]
+    fn equivalence() {
+        assert_eq!(equivalent("(a|b)*", "(a*b*)*"), Ok(()));
+        assert_eq!(equivalent("(ab)*a", "a(ba)*"), Ok(()));
+        assert_eq!(equivalent("a{2,3}", "aa|aaa"), Ok(()));
+        assert_eq!(equivalent("(a|b)*--(a|b)*b(a|b)*", "a*"), Ok(()));
+        assert_eq!(equivalent("(?s:.)*--a", "(?s:.)*--a"), Ok(()));
+        assert_eq!(equivalent("a(?=b)b", "ab"), Ok(()));
+        assert_eq!(equivalent("a*", "a+"), Err(String::new()));
+        assert_eq!(equivalent("(a|b)*abb", "(a|b)*bb"), Err("bb".to_string()));
+        assert_eq!(equivalent("x.*", "x(?s:.)*"), Err("x\n".to_string()));
+        // a character written in neither pattern
+        assert_eq!(equivalent(".*", "a*"), Err("b".to_string()));
+    }
+
+    fn is_subset_of(left: &str, right: &str) -> Result<(), String> {
+        KyomuRegex::compile(left).unwrap().is_subset_of(&KyomuRegex::compile(right).unwrap())
+    }
+
+    #[test]
+    fn inclusion() {
+        assert_eq!(is_subset_of("ab*", "a.*"), Ok(()));
+        assert_eq!(is_subset_of("(ab)+", "(a|b)*"), Ok(()));
+        assert_eq!(is_subset_of("a{2,4}", "a{1,}"), Ok(()));
+        assert_eq!(is_subset_of("a(?=b)(b|c)", "ab"), Ok(()));
+        assert_eq!(is_subset_of("--", "a"), Ok(()));
+        assert_eq!(is_subset_of("a.*", "ab*"), Err("aa".to_string()));
+        assert_eq!(is_subset_of("(a|b)*", "(ab)*"), Err("a".to_string()));
+        assert_eq!(is_subset_of("a?", "a"), Err(String::new()));
+    }
+
+    #[test]
+    fn emptiness() {
+        let compile = |pattern| KyomuRegex::compile(pattern).unwrap();
+        assert!(compile("a--a").is_empty());
+        assert!(compile("(a|b)+--.*b.*--a+").is_empty());
+        assert!(compile("a(?=b)c").is_empty());
+        assert!(!compile("a?").is_empty());
+        assert!(compile("(?s:.)*").is_universal());
+        assert!(compile("a*|((?s:.)*--a*)").is_universal());
+        assert!(!compile(".*").is_universal());
+        assert_eq!(compile("--").example(), None);
+        assert_eq!(compile("a*").example(), Some(String::new()));
+        assert_eq!(compile("c|b|(ab)+").example(), Some("b".to_string()));
+        assert_eq!(compile("(b|c)(b|a)a*--ca*").example(), Some("ba".to_string()));
+        assert_eq!(compile("c.").example(), Some("c\0".to_string()));
+        assert_eq!(compile("(?s:.)").example(), Some("\0".to_string()));
+        assert_eq!(compile("(?s:.)--\0").example(), Some("\u{1}".to_string()));
+        assert_eq!(compile("x(?<=ab)").example(), None);
+    }
+}
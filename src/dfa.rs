@@ -0,0 +1,642 @@
+use crate::{engine, DfaError, KyomuRegex};
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+
+// A set of characters the automaton does not tell apart
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum CharClass {
+    Char(char),       // exactly this character
+    Other(Vec<char>), // every character not listed
+}
+
+impl CharClass {
+    pub fn contains(&self, ch: char) -> bool {
+        match self {
+            CharClass::Char(c) => *c == ch,
+            CharClass::Other(chars) => !chars.contains(&ch),
+        }
+    }
+    // the number of characters in the class
+    pub fn size(&self) -> u128 {
+        // every code point but the surrogates
+        const CHARS: u128 = 0x110000 - 0x800;
+        match self {
+            CharClass::Char(_) => 1,
+            CharClass::Other(chars) => CHARS - chars.len() as u128,
+        }
+    }
+    // A character of the class, for reading it. Letters and digits come first,
+    // so that strings built from representatives stay readable.
+    pub fn representative(&self) -> char {
+        match self {
+            CharClass::Char(c) => *c,
+            CharClass::Other(chars) => ('a'..='z')
+                .chain('A'..='Z')
+                .chain('0'..='9')
+                .chain((0..=char::MAX as u32).filter_map(char::from_u32))
+                .find(|c| !chars.contains(c))
+                .unwrap(),
+        }
+    }
+}
+
+impl Display for CharClass {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CharClass::Char(c) => write!(f, "{}", KyomuRegex::Char(*c)),
+            CharClass::Other(chars) if chars.is_empty() => write!(f, "any"),
+            CharClass::Other(chars) => {
+                write!(f, "[^")?;
+                for c in chars {
+                    write!(f, "{}", KyomuRegex::Char(*c))?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+impl KyomuRegex {
+    // characters written literally somewhere in the regex
+    pub(crate) fn literals(&self, out: &mut BTreeSet<char>) {
+        use KyomuRegex::*;
+        match self {
+            Char(c) => {
+                out.insert(*c);
+            }
+            AnyExcept(chars) => out.extend(chars),
+            Any | Eps | Empty => {}
+            Concat(left, right) | Or(left, right) | And(left, right) => {
+                left.literals(out);
+                right.literals(out);
+            }
+            LookBehind(r, past) | NegLookBehind(r, past) => {
+                r.literals(out);
+                past.literals(out);
+            }
+            Star(r) | Plus(r) | Question(r) | Repeat(_, _, r) | Not(r) | Lazy(r) => r.literals(out),
+            LookAhead(r) | NegLookAhead(r) => r.literals(out),
+        }
+    }
+}
+
+// one class for each of the sorted `literals`, and then one for any other character
+pub(crate) fn classes(literals: &[char]) -> Vec<CharClass> {
+    let mut classes: Vec<CharClass> = literals.iter().map(|&c| CharClass::Char(c)).collect();
+    classes.push(CharClass::Other(literals.to_vec()));
+    classes
+}
+
+// The characters of the classes of `alphabet` for which `hits` holds, as a regex
+pub(crate) fn class_label(alphabet: &[char], hits: impl Fn(usize) -> bool) -> KyomuRegex {
+    use KyomuRegex::*;
+    let or = |chars: Vec<char>| {
+        chars
+            .into_iter()
+            .map(Char)
+            .reduce(|l, r| Or(Box::new(l), Box::new(r)))
+    };
+    let chars = alphabet.iter().enumerate();
+    if hits(alphabet.len()) {
+        // everything but the literals going elsewhere, as [^ab] rather than
+        // with -- or a lookahead, which other engines may not have
+        let excluded: Vec<char> = chars.filter(|&(i, _)| !hits(i)).map(|(_, &c)| c).collect();
+        match excluded.is_empty() {
+            true => Any,
+            false => AnyExcept(excluded),
+        }
+    } else {
+        or(chars.filter(|&(i, _)| hits(i)).map(|(_, &c)| c).collect()).unwrap()
+    }
+}
+
+// Deterministic automaton whose states are the derivatives of a regex.
+// State 0 is the regex itself.
+// All states are built up front, and a pattern like (a|b)*a(a|b){20} has
+// exponentially many, so building fails past `Dfa::STATE_LIMIT` of them.
+#[derive(Debug, Clone)]
+pub struct Dfa {
+    alphabet: Vec<char>,           // sorted literals; anything else falls in the last class
+    states: Vec<KyomuRegex>,
+    transitions: Vec<Vec<usize>>,  // state -> class -> state
+}
+
+impl Dfa {
+    pub const STATE_LIMIT: usize = 10_000;
+
+    pub fn new(regex: &KyomuRegex) -> Result<Self, DfaError> {
+        Dfa::with_state_limit(regex, Dfa::STATE_LIMIT)
+    }
+    // failing once there are more than `limit` states
+    pub fn with_state_limit(regex: &KyomuRegex, limit: usize) -> Result<Self, DfaError> {
+        let mut literals = BTreeSet::new();
+        regex.literals(&mut literals);
+        let alphabet: Vec<char> = literals.into_iter().collect();
+        let mut dfa = Dfa {
+            alphabet,
+            states: vec![regex.clone()],
+            transitions: vec![],
+        };
+        let representatives: Vec<char> = dfa.classes().iter().map(|c| c.representative()).collect();
+        let mut index = HashMap::from([(regex.clone(), 0)]);
+        let mut queue = VecDeque::from([0]);
+        // states are numbered in the order they are found, so rows are filled in order
+        while let Some(state) = queue.pop_front() {
+            let mut row = Vec::with_capacity(representatives.len());
+            for &ch in &representatives {
+                let next = dfa.states[state].derivative(ch);
+                let id = *index.entry(next.clone()).or_insert_with(|| {
+                    dfa.states.push(next);
+                    queue.push_back(dfa.states.len() - 1);
+                    dfa.states.len() - 1
+                });
+                row.push(id);
+            }
+            if dfa.states.len() > limit {
+                return Err(DfaError { limit });
+            }
+            dfa.transitions.push(row);
+        }
+        Ok(dfa)
+    }
+    // the characters `next` tells apart, one class per literal and then the rest
+    pub fn classes(&self) -> Vec<CharClass> {
+        classes(&self.alphabet)
+    }
+    fn class_of(&self, ch: char) -> usize {
+        self.alphabet.binary_search(&ch).unwrap_or(self.alphabet.len())
+    }
+    // the regex each state stands for
+    pub fn states(&self) -> &[KyomuRegex] {
+        &self.states
+    }
+    pub fn next(&self, state: usize, ch: char) -> usize {
+        self.transitions[state][self.class_of(ch)]
+    }
+    pub fn is_accepting(&self, state: usize) -> bool {
+        self.states[state].match_eps()
+    }
+    pub fn whole_match(&self, input: &str) -> bool {
+        self.is_accepting(input.chars().fold(0, |state, ch| self.next(state, ch)))
+    }
+    pub(crate) fn transitions(&self, state: usize) -> &[usize] {
+        &self.transitions[state]
+    }
+    // How many strings of `n` characters are accepted, or None if that does
+    // not fit in a u128.
+    // counts[s] is the number of accepted strings of the current length from s,
+    // None once it overflows; then so do the counts of the states before s.
+    pub fn count_of_length(&self, n: usize) -> Option<u128> {
+        let sizes: Vec<u128> = self.classes().iter().map(|c| c.size()).collect();
+        let mut counts: Vec<Option<u128>> = (0..self.states.len()).map(|s| Some(self.is_accepting(s) as u128)).collect();
+        for _ in 0..n {
+            counts = self
+                .transitions
+                .iter()
+                .map(|row| {
+                    let mut paths = row.iter().zip(&sizes).map(|(&t, &size)| counts[t]?.checked_mul(size));
+                    paths.try_fold(0u128, |sum, paths| sum.checked_add(paths?))
+                })
+                .collect();
+        }
+        counts[0]
+    }
+    // states from which some string is accepted
+    fn live(&self) -> Vec<bool> {
+        let mut live: Vec<bool> = (0..self.states.len())
+            .map(|s| self.is_accepting(s))
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (s, row) in self.transitions.iter().enumerate() {
+                if !live[s] && row.iter().any(|&t| live[t]) {
+                    live[s] = true;
+                    changed = true;
+                }
+            }
+        }
+        live
+    }
+    // the characters taking `row` to `target`, as a regex
+    fn label(&self, row: &[usize], target: usize) -> KyomuRegex {
+        class_label(&self.alphabet, |i| row[i] == target)
+    }
+    // A regex for the strings the automaton accepts, by state elimination.
+    // Each state is removed in turn, its in- and out-edges being joined by
+    // in ⋅ loop* ⋅ out, until only an edge from a new start to a new end remains.
+    // The order and `simplify` keep the result small, but it can still grow
+    // exponentially with the number of states: the 33 states of
+    // (a|b)*a(a|b){4} give some 18,000 characters.
+    pub fn to_regex(&self) -> KyomuRegex {
+        use KyomuRegex::*;
+        let n = self.states.len();
+        let (start, end) = (n, n + 1);
+        let live = self.live();
+        let mut edges: Vec<Vec<Option<KyomuRegex>>> = vec![vec![None; n + 2]; n + 2];
+        for s in (0..n).filter(|&s| live[s]) {
+            if self.is_accepting(s) {
+                edges[s][end] = Some(Eps);
+            }
+            for &t in &self.transitions[s] {
+                if live[t] && edges[s][t].is_none() {
+                    edges[s][t] = Some(self.label(&self.transitions[s], t));
+                }
+            }
+        }
+        if live[0] {
+            edges[start][0] = Some(Eps);
+        }
+        let mut remaining: Vec<usize> = (0..n).filter(|&s| live[s]).collect();
+        while !remaining.is_empty() {
+            // The state whose removal copies the least regex goes first: each
+            // in-edge is copied once per out-edge and the other way round
+            // (the weight of Delgado and Morais).
+            let weight = |k: usize| {
+                let ins: Vec<usize> = (0..n + 2)
+                    .filter(|&i| i != k)
+                    .filter_map(|i| edges[i][k].as_ref().map(size))
+                    .collect();
+                let outs: Vec<usize> = (0..n + 2)
+                    .filter(|&j| j != k)
+                    .filter_map(|j| edges[k][j].as_ref().map(size))
+                    .collect();
+                let repeat = edges[k][k].as_ref().map_or(0, size);
+                let pairs = ins.len() * outs.len();
+                ins.iter().sum::<usize>() * outs.len().saturating_sub(1)
+                    + outs.iter().sum::<usize>() * ins.len().saturating_sub(1)
+                    + repeat * pairs.saturating_sub(1)
+            };
+            let pos = (0..remaining.len())
+                .min_by_key(|&p| weight(remaining[p]))
+                .unwrap();
+            let k = remaining.swap_remove(pos);
+            let repeat = edges[k][k].take().map_or(Eps, |r| Star(Box::new(r)));
+            let ins: Vec<(usize, KyomuRegex)> = (0..n + 2)
+                .filter_map(|i| Some((i, edges[i][k].take()?)))
+                .collect();
+            let outs: Vec<(usize, KyomuRegex)> = (0..n + 2)
+                .filter_map(|j| Some((j, edges[k][j].take()?)))
+                .collect();
+            for (i, r) in &ins {
+                for (j, s) in &outs {
+                    let through = Concat(
+                        Box::new(r.clone()),
+                        Box::new(Concat(Box::new(repeat.clone()), Box::new(s.clone()))),
+                    );
+                    edges[*i][*j] = Some(
+                        match edges[*i][*j].take() {
+                            Some(direct) => Or(Box::new(direct), Box::new(through)),
+                            None => through,
+                        }
+                        .simplify(),
+                    );
+                }
+            }
+        }
+        edges[start][end].take().unwrap_or(Empty)
+    }
+}
+
+// the number of nodes in `r`
+fn size(r: &KyomuRegex) -> usize {
+    use KyomuRegex::*;
+    match r {
+        Char(_) | Any | AnyExcept(_) | Eps | Empty => 1,
+        Concat(left, right) | Or(left, right) | And(left, right) => 1 + size(left) + size(right),
+        LookBehind(r, _) | NegLookBehind(r, _) => 1 + size(r),
+        Star(r) | Plus(r) | Question(r) | Repeat(_, _, r) | Not(r) | Lazy(r) => 1 + size(r),
+        LookAhead(r) | NegLookAhead(r) => 1 + size(r),
+    }
+}
+
+// The states and transitions of a derivative DFA found so far
+#[derive(Debug, Clone)]
+struct Cache {
+    alphabet: Vec<char>,
+    root: KyomuRegex, // state 0, kept when the cache is cleared
+    limit: usize,     // the most states kept at once
+    states: Vec<KyomuRegex>,
+    conditions: Vec<KyomuRegex>, // δ of each state
+    index: HashMap<KyomuRegex, usize>,
+    transitions: Vec<Vec<Option<usize>>>, // state -> class -> state, once taken
+}
+
+impl Cache {
+    fn new(regex: &KyomuRegex, limit: usize) -> Self {
+        let mut literals = BTreeSet::new();
+        regex.literals(&mut literals);
+        let mut cache = Cache {
+            alphabet: literals.into_iter().collect(),
+            root: regex.clone(),
+            limit: limit.max(2),
+            states: vec![],
+            conditions: vec![],
+            index: HashMap::new(),
+            transitions: vec![],
+        };
+        cache.state(regex.clone());
+        cache
+    }
+    fn state(&mut self, regex: KyomuRegex) -> usize {
+        if let Some(&id) = self.index.get(&regex) {
+            return id;
+        }
+        self.index.insert(regex.clone(), self.states.len());
+        self.conditions.push(regex.delta());
+        self.states.push(regex);
+        self.transitions.push(vec![None; self.alphabet.len() + 1]);
+        self.states.len() - 1
+    }
+    fn next(&mut self, state: usize, ch: char) -> usize {
+        let class = self.alphabet.binary_search(&ch).unwrap_or(self.alphabet.len());
+        if let Some(next) = self.transitions[state][class] {
+            return next;
+        }
+        // every character of the class gives the same derivative
+        let derivative = self.states[state].derivative(ch);
+        if self.states.len() >= self.limit && !self.index.contains_key(&derivative) {
+            // full: start over from the root rather than grow without end,
+            // which leaves `state` stale, so the edge is not recorded
+            self.clear();
+            return self.state(derivative);
+        }
+        let next = self.state(derivative);
+        self.transitions[state][class] = Some(next);
+        next
+    }
+    fn clear(&mut self) {
+        self.states.clear();
+        self.conditions.clear();
+        self.index.clear();
+        self.transitions.clear();
+        self.state(self.root.clone());
+    }
+    // whether `state` matches the empty string when `rest` follows
+    fn accepts(&self, state: usize, rest: &str) -> bool {
+        self.conditions[state].holds_on(rest)
+    }
+}
+
+impl engine::Scan for Cache {
+    type State = usize;
+    fn step(&mut self, state: &usize, ch: char) -> usize {
+        self.next(*state, ch)
+    }
+    fn accepts(&mut self, state: &usize, rest: &str) -> bool {
+        Cache::accepts(self, *state, rest)
+    }
+    fn is_dead(&self, state: &usize) -> bool {
+        self.states[*state] == KyomuRegex::Empty
+    }
+}
+
+// The derivatives charged for lazy iterations, see `engine::CostedScan`,
+// found so far. `engine::preferred_end` holds several states at once, which
+// must outlive a clear, so they are keyed by the regex rather than an id.
+#[derive(Debug, Clone)]
+struct CostedCache {
+    alphabet: Vec<char>,
+    limit: usize, // the most states kept at once
+    states: HashMap<KyomuRegex, CostedState>,
+}
+
+#[derive(Debug, Clone)]
+struct CostedState {
+    condition: KyomuRegex,                             // δ of the state
+    transitions: Vec<Option<Vec<(KyomuRegex, u32)>>>, // class -> states, once taken
+}
+
+impl CostedCache {
+    fn new(regex: &KyomuRegex, limit: usize) -> Self {
+        let mut literals = BTreeSet::new();
+        regex.literals(&mut literals);
+        CostedCache { alphabet: literals.into_iter().collect(), limit: limit.max(2), states: HashMap::new() }
+    }
+    fn state(&mut self, regex: &KyomuRegex) -> &mut CostedState {
+        if self.states.len() >= self.limit && !self.states.contains_key(regex) {
+            // full: start over, the walk keeps its own copies of the regexes
+            self.states.clear();
+        }
+        let classes = self.alphabet.len() + 1;
+        self.states.entry(regex.clone()).or_insert_with(|| CostedState {
+            condition: regex.delta(),
+            transitions: vec![None; classes],
+        })
+    }
+}
+
+impl engine::CostedScan for CostedCache {
+    type State = KyomuRegex;
+    fn costed_step(&mut self, state: &KyomuRegex, ch: char) -> Vec<(KyomuRegex, u32)> {
+        let class = self.alphabet.binary_search(&ch).unwrap_or(self.alphabet.len());
+        let transitions = &mut self.state(state).transitions[class];
+        // every character of the class gives the same derivatives
+        transitions
+            .get_or_insert_with(|| engine::CostedScan::costed_step(&mut engine::Derivatives, state, ch))
+            .clone()
+    }
+    fn accepts(&mut self, state: &KyomuRegex, rest: &str) -> bool {
+        self.state(state).condition.holds_on(rest)
+    }
+}
+
+// Derivative DFA built while matching: each derivative is taken once and then
+// looked up, and states no input reaches are never built.
+// At most `LazyDfa::STATE_LIMIT` states are kept for each direction; past
+// that the cache is cleared and built again, so memory stays bounded even
+// on patterns whose DFA is exponential, at the cost of recomputing.
+// Lazy quantifiers weigh the ways to match, which one state cannot keep: for
+// those the forward direction caches the charged derivatives of each state
+// instead, and walks several at once, see `engine::preferred_end`.
+// The cache sits in a RefCell: a LazyDfa can move to another thread but not
+// be shared between threads (it is Send, not Sync). Give each thread a clone.
+#[derive(Debug, Clone)]
+pub struct LazyDfa {
+    regex: KyomuRegex,
+    forward: RefCell<Cache>,
+    costed: Option<RefCell<CostedCache>>, // when `regex` has lazy quantifiers
+    backward: RefCell<Cache>,             // on `engine::start_scan`
+}
+
+impl LazyDfa {
+    pub const STATE_LIMIT: usize = 10_000;
+
+    pub fn new(regex: &KyomuRegex) -> Self {
+        LazyDfa::with_state_limit(regex, LazyDfa::STATE_LIMIT)
+    }
+    // keeping at most `limit` states in each direction
+    pub fn with_state_limit(regex: &KyomuRegex, limit: usize) -> Self {
+        LazyDfa {
+            regex: regex.clone(),
+            forward: RefCell::new(Cache::new(regex, limit)),
+            costed: regex.is_lazy().then(|| RefCell::new(CostedCache::new(regex, limit))),
+            backward: RefCell::new(Cache::new(&engine::start_scan(regex), limit)),
+        }
+    }
+    // the number of states in the cache
+    pub fn states_built(&self) -> usize {
+        let costed = self.costed.as_ref().map_or(0, |costed| costed.borrow().states.len());
+        self.forward.borrow().states.len() + costed + self.backward.borrow().states.len()
+    }
+    pub fn whole_match(&self, input: &str) -> bool {
+        let mut cache = self.forward.borrow_mut();
+        let state = input.chars().fold(0, |state, ch| cache.next(state, ch));
+        cache.states[state].match_eps()
+    }
+    // Same result as `KyomuRegex::find`
+    pub fn find(&self, haystack: &str) -> Option<std::ops::Range<usize>> {
+        let start = engine::leftmost_start(&mut *self.backward.borrow_mut(), 0, haystack)?;
+        // lookbehinds also see the input before `start`
+        let init = haystack[..start].chars().fold(self.regex.clone(), |reg, ch| reg.tick(ch));
+        if let Some(costed) = &self.costed {
+            let end = engine::preferred_end(&mut *costed.borrow_mut(), init, haystack, start);
+            return end.map(|end| start..end);
+        }
+        let mut cache = self.forward.borrow_mut();
+        let state = cache.state(init);
+        let end = engine::longest_end(&mut *cache, state, haystack, start);
+        end.map(|end| start..end)
+    }
+}
+
+impl KyomuRegex {
+    // How many strings of `n` characters match, or None past u128::MAX.
+    // Fails when the automaton is too large, see `Dfa::new`.
+    pub fn count_of_length(&self, n: usize) -> Result<Option<u128>, DfaError> {
+        Ok(Dfa::new(self)?.count_of_length(n))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::strings_up_to;
+
+    #[test]
+    fn finite_states() {
+        let regex = KyomuRegex::compile("(a|b)*abb").unwrap();
+        let dfa = Dfa::new(&regex).unwrap();
+        // the four states of the minimal automaton and the dead state for other characters
+        assert_eq!(dfa.states().len(), 5);
+        assert_eq!(dfa.classes().len(), 3);
+        for input in ["abb", "aabb", "babb", "ab", "abba", "abbc", ""] {
+            assert_eq!(dfa.whole_match(input), regex.whole_match(input), "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn agrees_with_derivatives() {
+        for pattern in ["a{2,3}b*", "(ab|a)*--a*", ".*(?<=ab)c", "(?=ab)..|x", "(?i)k+"] {
+            let regex = KyomuRegex::compile(pattern).unwrap();
+            let dfa = Dfa::new(&regex).unwrap();
+            for input in ["", "aa", "aab", "aaab", "abab", "ab", "abc", "xabc", "x\n", "Kk\u{212A}"] {
+                assert_eq!(dfa.whole_match(input), regex.whole_match(input), "{} on {:?}", pattern, input);
+            }
+        }
+    }
+
+    #[test]
+    fn starred_lookahead() {
+        let inputs = strings_up_to(&['a', 'b', 'c'], 6);
+        for pattern in [
+            "(a(?=b)|b)*",
+            "(a(?=b))*",
+            "(a(?=.*b))*c?",
+            "(a(?!.*b)|b)*c",
+            "((?=a*b)a|b|c(?=c*a))*",
+        ] {
+            let regex = KyomuRegex::compile(pattern).unwrap();
+            let dfa = Dfa::new(&regex).unwrap();
+            assert!(dfa.states().len() < 20, "{}: {} states", pattern, dfa.states().len());
+            for input in &inputs {
+                assert_eq!(dfa.whole_match(input), regex.whole_match(input), "{} on {:?}", pattern, input);
+            }
+            assert_eq!(regex.equivalent(&dfa.to_regex()), Ok(()), "{}", pattern);
+            let sample = regex.generate(&mut crate::Rng::new(0), 6).unwrap().unwrap();
+            assert!(regex.whole_match(&sample), "{} gave {:?}", pattern, sample);
+        }
+        assert_eq!(KyomuRegex::compile("(a(?=b)|b)*").unwrap().count_of_length(3), Ok(Some(3)));
+    }
+
+    #[test]
+    fn state_limit() {
+        let regex = KyomuRegex::compile("(a|b)*a(a|b){6}").unwrap();
+        assert_eq!(Dfa::with_state_limit(&regex, 20).unwrap_err(), DfaError { limit: 20 });
+        assert_eq!(DfaError { limit: 20 }.to_string(), "The DFA has more than 20 states");
+        assert_eq!(Dfa::with_state_limit(&regex, 200).unwrap().states().len(), 129);
+    }
+
+    #[test]
+    fn char_class() {
+        let other = CharClass::Other(vec!['\0', 'a']);
+        assert!(other.contains('b'));
+        assert!(!other.contains('a'));
+        assert_eq!(other.representative(), 'b');
+        let letters: Vec<char> = ('a'..='z').chain('A'..='Z').chain('0'..='9').collect();
+        assert_eq!(CharClass::Other(letters).representative(), '\0');
+        assert_eq!(other.to_string(), r"[^\0a]");
+        assert_eq!(CharClass::Char('*').to_string(), r"\*");
+    }
+
+    #[test]
+    fn count_strings() {
+        let count = |pattern, n| KyomuRegex::compile(pattern).unwrap().count_of_length(n).unwrap();
+        assert_eq!(count("(a|b){3}", 3), Some(8));
+        assert_eq!(count("(a|b){3}", 2), Some(0));
+        assert_eq!(count("a*b*", 3), Some(4));
+        // no two a's in a row
+        assert_eq!(count("(a|b)*--.*aa.*", 5), Some(13));
+        assert_eq!(count("(a|b)*(?<=ab)", 3), Some(2));
+        assert_eq!(count("(?s:.)", 1), Some(1_112_064));
+        assert_eq!(count(".{2}", 2), Some(1_112_063 * 1_112_063));
+        assert_eq!(count("(?s:.)*", 6), Some(1_112_064u128.pow(6)));
+        assert_eq!(count("(?s:.)*", 10), None);
+        // a state past the start may overflow one length ahead of it
+        assert_eq!(count("a|b(?s:.)*", 7), Some(1_112_064u128.pow(6)));
+        assert_eq!(count("", 0), Some(1));
+        let explodes = KyomuRegex::compile("(a|b)*a(a|b){14}").unwrap();
+        assert_eq!(explodes.count_of_length(3), Err(DfaError { limit: Dfa::STATE_LIMIT }));
+    }
+
+    #[test]
+    fn back_to_regex() {
+        let to_regex = |pattern| Dfa::new(&KyomuRegex::compile(pattern).unwrap()).unwrap().to_regex();
+        assert_eq!(to_regex("ab").to_string(), "ab");
+        assert_eq!(to_regex("a*").to_string(), "a*");
+        assert_eq!(to_regex("a--a"), KyomuRegex::Empty);
+        assert_eq!(to_regex("(?s:.)").to_string(), "(?s:.)");
+        assert_eq!(to_regex(".").to_string(), ".");
+        // r & ~s without the & and ~
+        assert_eq!(to_regex("(a|b)*--.*aa.*").to_string(), "(a?b)*a?");
+        assert_eq!(to_regex("(?s:.)*--(?s:.)*a(?s:.)*").to_string(), "[^a]*");
+        assert_eq!(to_regex("x(?s:.)--x(a|b)").to_string(), "x[^ab]");
+        let big = Dfa::new(&KyomuRegex::compile("(a|b)*a(a|b){4}").unwrap()).unwrap().to_regex();
+        assert!(big.to_string().len() < 25_000);
+        for pattern in [
+            "(a|b)*abb",
+            "(a|b)*--.*aa.*",
+            "a{2,4}|b+",
+            "(ab|a)*(?<=ab)",
+            "x(?=y)..",
+            "(a.)*--(ab)*",
+            "(?s:.)*--.*ab.*",
+            "",
+        ] {
+            let regex = KyomuRegex::compile(pattern).unwrap();
+            let back = Dfa::new(&regex).unwrap().to_regex();
+            // nothing other engines may lack
+            for operator in ["--", "(?=", "(?!", "(?<=", "(?<!"] {
+                assert!(!back.to_string().contains(operator), "{} became {}", pattern, back);
+            }
+            assert_eq!(
+                back.equivalent(&regex),
+                Ok(()),
+                "{} became {}",
+                pattern,
+                back
+            );
+        }
+    }
+}
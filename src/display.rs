@@ -0,0 +1,391 @@
+use crate::dfa::{class_label, classes};
+use crate::{Dfa, KyomuRegex};
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fmt::{Display, Formatter, Result};
+
+// The most derivatives `Display` takes to spell out lookbehinds
+const SPELL_OUT_LIMIT: usize = 64;
+// The most DFA states `Display` turns back into a regex when spelling out
+// fails; `Dfa::to_regex` grows exponentially with them
+const TO_REGEX_LIMIT: usize = 32;
+
+// Binding strength, weakest first
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+enum Prec {
+    Diff,   // r--s
+    Or,     // r|s
+    Concat, // rs
+    Quant,  // r*
+    Atom,   // operand of a quantifier
+}
+
+// Characters with a meaning of their own in a pattern
+fn is_meta(ch: char) -> bool {
+    matches!(ch, '\\' | '|' | '(' | ')' | '*' | '+' | '?' | '{' | '-' | '.' | '[')
+}
+
+fn fmt_char(ch: char, f: &mut Formatter<'_>) -> Result {
+    match ch {
+        '\n' => write!(f, "\\n"),
+        '\t' => write!(f, "\\t"),
+        '\r' => write!(f, "\\r"),
+        '\0' => write!(f, "\\0"),
+        c if c.is_control() => write!(f, "\\u{{{:X}}}", c as u32),
+        c if is_meta(c) => write!(f, "\\{}", c),
+        c => write!(f, "{}", c),
+    }
+}
+
+// a character inside [^...]
+fn fmt_class_char(ch: char, f: &mut Formatter<'_>) -> Result {
+    match ch {
+        ']' | '^' => write!(f, "\\{}", ch),
+        c => fmt_char(c, f),
+    }
+}
+
+impl KyomuRegex {
+    // A repetition no count satisfies, like {4,2} or {4,2}?, which a pattern
+    // cannot write
+    fn is_empty_repeat(&self) -> bool {
+        use KyomuRegex::*;
+        match self {
+            Repeat(min, Some(max), _) => min > max,
+            Lazy(r) => r.is_empty_repeat(),
+            _ => false,
+        }
+    }
+
+    fn prec(&self) -> Prec {
+        use KyomuRegex::*;
+        match self {
+            Empty | Not(_) | And(_, _) => Prec::Diff,
+            _ if self.is_empty_repeat() => Prec::Diff,
+            Or(_, _) => Prec::Or,
+            Eps | Concat(_, _) => Prec::Concat,
+            Star(_) | Plus(_) | Question(_) | Repeat(_, _, _) => Prec::Quant,
+            Lazy(r) => r.prec(),
+            _ => Prec::Atom,
+        }
+    }
+
+    // Whether every lookbehind has read just `read`, the input since the
+    // start of `self`. Such lookbehinds print as written.
+    fn lookbehinds_read(&self, read: &[char]) -> bool {
+        use KyomuRegex::*;
+        match self {
+            LookBehind(r, past) | NegLookBehind(r, past) => {
+                let unread = Concat(Box::new(Star(Box::new(Any))), r.clone());
+                [unread.simplify(), unread]
+                    .into_iter()
+                    .any(|p| read.iter().fold(p, |p, &ch| p.derivative(ch)) == **past)
+            }
+            Char(_) | Any | AnyExcept(_) | Eps | Empty | LookAhead(_) | NegLookAhead(_) => true,
+            Concat(left, right) | Or(left, right) | And(left, right) => {
+                left.lookbehinds_read(read) && right.lookbehinds_read(read)
+            }
+            Star(r) | Plus(r) | Question(r) | Repeat(_, _, r) | Not(r) | Lazy(r) => {
+                r.lookbehinds_read(read)
+            }
+        }
+    }
+
+    // An equivalent regex whose lookbehinds print as written. The first
+    // characters are spelled out, δ(r) | a⋅D_a(r) | b⋅D_b(r) | ..., until the
+    // lookbehinds have read nothing but them: a body of at most n characters
+    // no longer sees what came before once n characters are spelled out.
+    // None after `budget` derivatives, e.g. for a body like a.*b.
+    fn spell_out(&self, read: &mut Vec<char>, budget: &mut usize) -> Option<KyomuRegex> {
+        use KyomuRegex::*;
+        if self.lookbehinds_read(read) {
+            return Some(self.clone());
+        }
+        *budget = budget.checked_sub(1)?;
+        let mut literals = BTreeSet::new();
+        self.literals(&mut literals);
+        let alphabet: Vec<char> = literals.into_iter().collect();
+        let mut terms = vec![self.delta()];
+        for (i, class) in classes(&alphabet).iter().enumerate() {
+            let ch = class.representative();
+            let next = self.derivative(ch);
+            if next == Empty {
+                continue;
+            }
+            read.push(ch);
+            let rest = next.spell_out(read, budget)?;
+            read.pop();
+            terms.push(Concat(Box::new(class_label(&alphabet, |j| j == i)), Box::new(rest)));
+        }
+        terms.retain(|r| *r != Empty);
+        Some(terms.into_iter().reduce(|l, r| Or(Box::new(l), Box::new(r))).unwrap_or(Empty))
+    }
+
+    // quantifier suffix, if `self` is a quantifier
+    fn fmt_quantifier(&self, f: &mut Formatter<'_>) -> Option<Result> {
+        use KyomuRegex::*;
+        let (r, suffix) = match self {
+            Star(r) => (r, "*".to_string()),
+            Plus(r) => (r, "+".to_string()),
+            Question(r) => (r, "?".to_string()),
+            Repeat(min, Some(max), r) if min == max => (r, format!("{{{}}}", min)),
+            Repeat(min, Some(max), r) => (r, format!("{{{},{}}}", min, max)),
+            Repeat(min, None, r) => (r, format!("{{{},}}", min)),
+            _ => return None,
+        };
+        Some(r.fmt_prec(f, Prec::Atom).and_then(|_| write!(f, "{}", suffix)))
+    }
+
+    fn fmt_prec(&self, f: &mut Formatter<'_>, prec: Prec) -> Result {
+        use KyomuRegex::*;
+        if self.prec() < prec {
+            write!(f, "(")?;
+            self.fmt_prec(f, Prec::Diff)?;
+            return write!(f, ")");
+        }
+        match self {
+            Char(c) => fmt_char(*c, f),
+            // the wildcard in KyomuRegex also matches a newline
+            Any => write!(f, "(?s:.)"),
+            // . without (?s)
+            AnyExcept(chars) if *chars == ['\n'] => write!(f, "."),
+            AnyExcept(chars) => {
+                write!(f, "[^")?;
+                for &c in chars {
+                    fmt_class_char(c, f)?;
+                }
+                write!(f, "]")
+            }
+            Eps => Ok(()),
+            // ε & ~ε, also for a repetition no count satisfies, like {4,2}
+            Empty => write!(f, "--"),
+            _ if self.is_empty_repeat() => write!(f, "--"),
+            Concat(left, right) => {
+                left.fmt_prec(f, Prec::Concat)?;
+                right.fmt_prec(f, Prec::Concat)
+            }
+            Or(left, right) => {
+                left.fmt_prec(f, Prec::Or)?;
+                write!(f, "|")?;
+                right.fmt_prec(f, Prec::Or)
+            }
+            And(left, right) => {
+                left.fmt_prec(f, Prec::Diff)?;
+                write!(f, "--")?;
+                match &**right {
+                    Not(right) => right.fmt_prec(f, Prec::Or),
+                    // r & s = r -- ~s
+                    right => Not(Box::new(right.clone())).fmt_prec(f, Prec::Or),
+                }
+            }
+            // ~r = .* -- r
+            Not(r) => {
+                write!(f, "(?s:.)*--")?;
+                r.fmt_prec(f, Prec::Or)
+            }
+            Lazy(r) => match r.fmt_quantifier(f) {
+                Some(result) => result.and_then(|_| write!(f, "?")),
+                None => r.fmt_prec(f, prec),
+            },
+            LookAhead(r) => write_group(f, "(?=", r),
+            NegLookAhead(r) => write_group(f, "(?!", r),
+            // the input read so far is not shown, see `Display::fmt`
+            LookBehind(r, _) => write_group(f, "(?<=", r),
+            NegLookBehind(r, _) => write_group(f, "(?<!", r),
+            _ => self.fmt_quantifier(f).unwrap(),
+        }
+    }
+}
+
+fn write_group(f: &mut Formatter<'_>, open: &str, r: &KyomuRegex) -> Result {
+    write!(f, "{}", open)?;
+    r.fmt_prec(f, Prec::Diff)?;
+    write!(f, ")")
+}
+
+impl KyomuRegex {
+    // The regex to print for `self`: itself, or when its lookbehinds have
+    // read input, which a pattern cannot mention, itself with the first
+    // characters spelled out (see `spell_out`). If that takes more than
+    // `SPELL_OUT_LIMIT` derivatives, as for a body like a.*b, the regex of
+    // its DFA, which has no lookarounds. None if the DFA has more than
+    // `TO_REGEX_LIMIT` states.
+    fn printable(&self) -> Option<Cow<'_, KyomuRegex>> {
+        if self.lookbehinds_read(&[]) {
+            return Some(Cow::Borrowed(self));
+        }
+        if let Some(r) = self.spell_out(&mut vec![], &mut { SPELL_OUT_LIMIT }) {
+            return Some(Cow::Owned(r));
+        }
+        let dfa = Dfa::with_state_limit(self, TO_REGEX_LIMIT).ok()?;
+        Some(Cow::Owned(dfa.to_regex()))
+    }
+    // pattern text that `KyomuRegex::compile` parses back to an equivalent
+    // regex, if `printable` finds one
+    pub(crate) fn to_pattern(&self) -> Option<String> {
+        self.printable().map(|r| AsWritten(&r).to_string())
+    }
+}
+
+// a regex printed without spelling anything out
+struct AsWritten<'a>(&'a KyomuRegex);
+
+impl Display for AsWritten<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        self.0.fmt_prec(f, Prec::Diff)
+    }
+}
+
+// Prints pattern text that `KyomuRegex::compile` parses back to an equivalent
+// regex, see `printable`. Past its limits the lookbehinds are printed as
+// written, which leaves out the input they have read, after a (?#...)
+// comment that `compile` rejects, so the text is never taken for the regex.
+impl Display for KyomuRegex {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+        match self.printable() {
+            Some(r) => r.fmt_prec(f, Prec::Diff),
+            None => {
+                write!(f, "(?#lookbehinds have read input)")?;
+                self.fmt_prec(f, Prec::Diff)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::KyomuRegex;
+    use crate::KyomuRegex::*;
+    use crate::tests::strings_up_to;
+
+    fn display(pattern: &str) -> String {
+        KyomuRegex::compile(pattern).unwrap().to_string()
+    }
+
+    #[test]
+    fn minimal_parentheses() {
+        assert_eq!(display("a|(bc)*"), "a|(bc)*");
+        assert_eq!(display("(a|b)c"), "(a|b)c");
+        assert_eq!(display("a*b+?c{2}d{2,}e{,3}?"), "a*b+?c{2}d{2,}e{0,3}?");
+        assert_eq!(display(r"a.\-\-\n"), r"a.\-\-\n");
+        assert_eq!(display(r"a\.b(?s:.)"), r"a\.b(?s:.)");
+        assert_eq!(display("a--b|c--(d--e)"), "a--b|c--(d--e)");
+        assert_eq!(display("(?=a)(?<!b)"), "(?=a)(?<!b)");
+        assert_eq!(display("(?s:.)()"), "(?s:.)");
+        assert_eq!(display(r"[^\]a^]b[.]\["), r"[^\]\^a]b\.\[");
+        assert_eq!(display("[^]"), "(?s:.)");
+        assert_eq!(Empty.to_string(), "--");
+        assert_eq!(Star(Box::new(Eps)).to_string(), "()*");
+        assert_eq!(Star(Box::new(Star(Box::new(Char('a'))))).to_string(), "(a*)*");
+        assert_eq!(Not(Box::new(Char('a'))).to_string(), "(?s:.)*--a");
+        assert_eq!(Repeat(4, Some(2), Box::new(Char('a'))).to_string(), "--");
+        let never = Repeat(4, Some(2), Box::new(Char('a')));
+        assert_eq!(Concat(Box::new(Char('b')), Box::new(never)).to_string(), "b(--)");
+        let lazy_never = Lazy(Box::new(Repeat(4, Some(2), Box::new(Char('a')))));
+        assert_eq!(Concat(Box::new(Char('b')), Box::new(lazy_never)).to_string(), "b(--)");
+    }
+
+    #[test]
+    fn read_lookbehind() {
+        let r = KyomuRegex::compile("(a|b)*(?<=ab)c").unwrap();
+        let d = r.derivative('a').derivative('b');
+        let parsed = KyomuRegex::compile(&d.to_string()).unwrap();
+        assert!(d.whole_match("c"));
+        assert!(parsed.whole_match("c"));
+        assert_eq!(parsed.equivalent(&d), Ok(()));
+        // before any input the lookbehind prints as written
+        assert_eq!(r.to_string(), "(a|b)*(?<=ab)c");
+        // only the first two characters are spelled out, whatever the DFA size
+        let r = KyomuRegex::compile("(a|b)*a(a|b){5}(?<=ab)").unwrap();
+        let d = r.derivative('a').derivative('a');
+        let text = d.to_string();
+        assert!(text.len() < 300, "{}", text);
+        assert_eq!(KyomuRegex::compile(&text).unwrap().equivalent(&d), Ok(()));
+        // a body of unbounded length goes through the DFA
+        let r = KyomuRegex::compile("(a|b)*(?<=a(a|b)*b)").unwrap();
+        let d = r.derivative('a');
+        assert!(d.whole_match("b"));
+        let parsed = KyomuRegex::compile(&d.to_string()).unwrap();
+        assert_eq!(parsed.equivalent(&d), Ok(()), "{}", d);
+        // and with too many states, it is not mistaken for a pattern
+        let r = KyomuRegex::compile("(a|b)*a(a|b){4}(?<=a(a|b)*b)").unwrap();
+        let text = r.derivative('a').to_string();
+        assert!(text.starts_with("(?#"), "{}", text);
+        assert!(KyomuRegex::compile(&text).is_err());
+    }
+
+    // xorshift, enough to build test cases
+    struct Gen(u64);
+    impl Gen {
+        fn next(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+        fn repeat(&mut self, depth: u32) -> KyomuRegex {
+            let min = self.next(3) as u32;
+            // min / 2 is sometimes below min, which matches nothing
+            let max = [None, Some(min), Some(min + 1), Some(min / 2)][self.next(4) as usize];
+            Repeat(min, max, Box::new(self.regex(depth)))
+        }
+        fn regex(&mut self, depth: u32) -> KyomuRegex {
+            let b = |r: KyomuRegex| Box::new(r);
+            if depth == 0 {
+                return match self.next(7) {
+                    0 => Eps,
+                    1 => Empty,
+                    2 => Any,
+                    3 => Char('-'),
+                    4 => Char('a'),
+                    5 => Char('.'),
+                    _ => Char('b'),
+                };
+            }
+            let d = depth - 1;
+            match self.next(14) {
+                0 => Concat(b(self.regex(d)), b(self.regex(d))),
+                1 => Or(b(self.regex(d)), b(self.regex(d))),
+                2 => Star(b(self.regex(d))),
+                3 => Plus(b(self.regex(d))),
+                4 => Question(b(self.regex(d))),
+                5 => self.repeat(d),
+                6 => And(b(self.regex(d)), b(self.regex(d))),
+                7 => Not(b(self.regex(d))),
+                8 => match self.next(2) {
+                    0 => Lazy(b(Star(b(self.regex(d))))),
+                    _ => Lazy(b(self.repeat(d))),
+                },
+                // lookarounds may not nest
+                9 => LookAhead(b(Concat(b(self.regex(0)), b(Star(b(self.regex(0))))))),
+                10 => NegLookAhead(b(Concat(b(self.regex(0)), b(self.regex(0))))),
+                11 => AnyExcept([vec!['\n'], vec![']', 'a'], vec![]][self.next(3) as usize].clone()),
+                12 => {
+                    let body = b(Concat(b(self.regex(0)), b(self.regex(0))));
+                    let past = b(Concat(b(Star(b(Any))), body.clone()));
+                    match self.next(2) {
+                        0 => LookBehind(body, past),
+                        _ => NegLookBehind(body, past),
+                    }
+                }
+                _ => Concat(b(self.regex(d)), b(self.regex(d))),
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let inputs = strings_up_to(&['a', 'b', '-', '.', '\n'], 3);
+        let mut gen = Gen(0x2545F4914F6CDD1D);
+        for _ in 0..300 {
+            let r = gen.regex(4);
+            for r in [r.clone(), r.derivative('a'), r.derivative('-'), r.derivative('a').derivative('b')] {
+                let text = r.to_string();
+                let parsed = KyomuRegex::compile(&text).unwrap_or_else(|e| panic!("{}: {}", text, e));
+                for input in &inputs {
+                    assert_eq!(r.whole_match(input), parsed.whole_match(input), "{} on {:?}", text, input);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+// Graphviz output for the syntax tree and the derivative automaton
+use crate::dfa::{CharClass, Dfa};
+use crate::KyomuRegex;
+use std::fmt::Write;
+
+// quotes `label` as a DOT string
+fn quote(label: &str) -> String {
+    format!("\"{}\"", label.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl KyomuRegex {
+    // operator at the root and the subtrees below it
+    fn node(&self) -> (String, Vec<&KyomuRegex>) {
+        use KyomuRegex::*;
+        match self {
+            Char(_) | Any | AnyExcept(_) => (self.to_string(), vec![]),
+            Eps => ("ε".to_string(), vec![]),
+            Empty => ("∅".to_string(), vec![]),
+            Concat(left, right) => ("⋅".to_string(), vec![left, right]),
+            Or(left, right) => ("|".to_string(), vec![left, right]),
+            Star(r) => ("*".to_string(), vec![r]),
+            Plus(r) => ("+".to_string(), vec![r]),
+            Question(r) => ("?".to_string(), vec![r]),
+            Repeat(min, Some(max), r) => (format!("{{{},{}}}", min, max), vec![r]),
+            Repeat(min, None, r) => (format!("{{{},}}", min), vec![r]),
+            And(left, right) => ("&".to_string(), vec![left, right]),
+            Not(r) => ("~".to_string(), vec![r]),
+            Lazy(r) => ("lazy".to_string(), vec![r]),
+            LookAhead(r) => ("(?=)".to_string(), vec![r]),
+            NegLookAhead(r) => ("(?!)".to_string(), vec![r]),
+            // the input read so far is not shown
+            LookBehind(r, _) => ("(?<=)".to_string(), vec![r]),
+            NegLookBehind(r, _) => ("(?<!)".to_string(), vec![r]),
+        }
+    }
+
+    // Graphviz drawing of the syntax tree
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph regex {\n    ordering=out;\n");
+        let mut stack = vec![(self, 0)];
+        let mut count = 1;
+        while let Some((regex, id)) = stack.pop() {
+            let (label, children) = regex.node();
+            writeln!(out, "    n{} [label={}];", id, quote(&label)).unwrap();
+            let ids = count..count + children.len();
+            count = ids.end;
+            for child in ids.clone() {
+                writeln!(out, "    n{} -> n{};", id, child).unwrap();
+            }
+            // pushed in reverse, so nodes are written in preorder
+            stack.extend(children.into_iter().zip(ids).rev());
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+// the most characters of a regex shown in a state label
+const LABEL_LIMIT: usize = 40;
+
+// `regex` as pattern text cut to `LABEL_LIMIT` characters, or the state name
+// when its lookbehinds cannot be printed
+fn state_label(state: usize, regex: &KyomuRegex) -> String {
+    match regex.to_pattern() {
+        Some(text) if text.chars().count() > LABEL_LIMIT => {
+            format!("{}…", text.chars().take(LABEL_LIMIT).collect::<String>())
+        }
+        Some(text) => text,
+        None => format!("s{}", state),
+    }
+}
+
+impl Dfa {
+    // Graphviz drawing of the automaton
+    // Each state is labelled with its regex, shortened, see `state_label`.
+    // The dead state ∅ and the edges into it are left out.
+    pub fn to_dot(&self) -> String {
+        let classes = self.classes();
+        let mut out = String::from("digraph dfa {\n    rankdir=LR;\n");
+        out.push_str("    start [shape=point];\n    start -> s0;\n");
+        let dead = |state: usize| self.states()[state] == KyomuRegex::Empty;
+        for (state, regex) in self.states().iter().enumerate().filter(|&(s, _)| !dead(s)) {
+            let shape = if self.is_accepting(state) { "doublecircle" } else { "circle" };
+            writeln!(out, "    s{} [label={}, shape={}];", state, quote(&state_label(state, regex)), shape).unwrap();
+        }
+        for state in (0..self.states().len()).filter(|&s| !dead(s)) {
+            let row = self.transitions(state);
+            let mut targets: Vec<usize> = row.iter().copied().filter(|&t| !dead(t)).collect();
+            targets.sort();
+            targets.dedup();
+            for target in targets {
+                let label = edge_label(&classes, row, target);
+                writeln!(out, "    s{} -> s{} [label={}];", state, target, quote(&label)).unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+// the characters taking `row` to `target`, as one class
+fn edge_label(classes: &[CharClass], row: &[usize], target: usize) -> String {
+    // the last class holds the characters not written in the regex
+    let chars = &classes[..classes.len() - 1];
+    let hits = |i: usize| row[i] == target;
+    if hits(chars.len()) {
+        // everything but the literals going elsewhere
+        let excluded = chars.iter().enumerate().filter(|&(i, _)| !hits(i));
+        CharClass::Other(excluded.map(|(_, c)| c.representative()).collect()).to_string()
+    } else {
+        let chars: Vec<String> = chars
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| hits(i))
+            .map(|(_, c)| c.to_string())
+            .collect();
+        if chars.len() == 1 {
+            chars.concat()
+        } else {
+            format!("[{}]", chars.concat())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Dfa, KyomuRegex};
+
+    #[test]
+    fn syntax_tree() {
+        let regex = KyomuRegex::compile("a|b*\"").unwrap();
+        assert_eq!(
+            regex.to_dot(),
+            "digraph regex {\n    ordering=out;\n    n0 [label=\"|\"];\n    n0 -> n1;\n    n0 -> n2;\n    \
+             n1 [label=\"a\"];\n    n2 [label=\"⋅\"];\n    n2 -> n3;\n    n2 -> n4;\n    \
+             n3 [label=\"*\"];\n    n3 -> n5;\n    n5 [label=\"b\"];\n    n4 [label=\"\\\"\"];\n}\n"
+        );
+    }
+
+    #[test]
+    fn automaton() {
+        let dot = Dfa::new(&KyomuRegex::compile("a*b").unwrap()).unwrap().to_dot();
+        assert!(dot.contains("s0 [label=\"a*b\", shape=circle];"), "{}", dot);
+        assert!(dot.contains("s0 -> s0 [label=\"a\"];"), "{}", dot);
+        assert!(dot.contains("s0 -> s1 [label=\"b\"];"), "{}", dot);
+        assert!(dot.contains("s1 [label=\"\", shape=doublecircle];"), "{}", dot);
+        // no edges into the dead state
+        assert_eq!(dot.matches("->").count(), 3);
+
+        let dot = Dfa::new(&KyomuRegex::compile("a.*").unwrap()).unwrap().to_dot();
+        assert!(dot.contains("s2 -> s2 [label=\"[^\\\\n]\"];"), "{}", dot);
+
+        // long labels are cut short, lookbehinds and all
+        let dfa = Dfa::new(&KyomuRegex::compile("(a|b)*a(a|b){4}(?<=ab)").unwrap()).unwrap();
+        let dot = dfa.to_dot();
+        assert!(dot.contains("…"), "{}", dot);
+        assert!(dot.len() < 200 * dfa.states().len(), "{}", dot);
+        // a lookbehind that has read input and cannot be printed
+        let regex = KyomuRegex::compile("(a|b)*a(a|b){4}(?<=a(a|b)*b)").unwrap();
+        let dot = Dfa::new(&regex).unwrap().to_dot();
+        assert!(dot.contains("s1 [label=\"s1\""), "{}", dot);
+    }
+}
@@ -0,0 +1,355 @@
+// Interchangeable matching engines
+use crate::{KyomuRegex, LazyDfa, Nfa};
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::ops::Range;
+
+// Which algorithm runs a compiled pattern, see `KyomuRegexBuilder::engine`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    #[default]
+    Auto, // picked from the pattern
+    Derivative, // a derivative taken for every character
+    LazyDfa,    // derivatives cached as DFA states; with lazy quantifiers, charged ones
+    Nfa,        // sets of partial derivatives
+}
+
+// What every engine can do; all of them give the same results.
+// Engines are Send, so `KyomuRegexBuilder::build_matcher` returns a matcher
+// that can move to another thread. They are not all Sync: the lazy DFA fills
+// its cache through `&self`, so build one matcher per thread to share a pattern.
+pub trait Matcher {
+    // whether all of `input` matches
+    fn whole_match(&self, input: &str) -> bool;
+    // the leftmost match, as in `KyomuRegex::find`
+    fn find(&self, haystack: &str) -> Option<Range<usize>>;
+    // whether some part of `haystack` matches
+    fn is_match(&self, haystack: &str) -> bool {
+        self.find(haystack).is_some()
+    }
+}
+
+impl Matcher for KyomuRegex {
+    fn whole_match(&self, input: &str) -> bool {
+        KyomuRegex::whole_match(self, input)
+    }
+    fn find(&self, haystack: &str) -> Option<Range<usize>> {
+        KyomuRegex::find(self, haystack)
+    }
+}
+
+impl Matcher for LazyDfa {
+    fn whole_match(&self, input: &str) -> bool {
+        LazyDfa::whole_match(self, input)
+    }
+    fn find(&self, haystack: &str) -> Option<Range<usize>> {
+        LazyDfa::find(self, haystack)
+    }
+}
+
+impl Matcher for Nfa {
+    fn whole_match(&self, input: &str) -> bool {
+        Nfa::whole_match(self, input)
+    }
+    fn find(&self, haystack: &str) -> Option<Range<usize>> {
+        Nfa::find(self, haystack)
+    }
+}
+
+// One direction of an engine, as `leftmost_start` and `longest_end` walk it
+pub(crate) trait Scan {
+    type State;
+    // the state after reading `ch`
+    fn step(&mut self, state: &Self::State, ch: char) -> Self::State;
+    // whether `state` matches the empty string when `rest` follows
+    fn accepts(&mut self, state: &Self::State, rest: &str) -> bool;
+    // whether no input leads from `state` to a match
+    fn is_dead(&self, state: &Self::State) -> bool;
+}
+
+// Derivatives taken as they come, with no cache
+pub(crate) struct Derivatives;
+
+impl Scan for Derivatives {
+    type State = KyomuRegex;
+    fn step(&mut self, state: &KyomuRegex, ch: char) -> KyomuRegex {
+        state.derivative(ch)
+    }
+    fn accepts(&mut self, state: &KyomuRegex, rest: &str) -> bool {
+        state.delta().holds_on(rest)
+    }
+    fn is_dead(&self, state: &KyomuRegex) -> bool {
+        *state == KyomuRegex::Empty
+    }
+}
+
+// One direction of an engine as `preferred_end` walks it, when the regex has
+// lazy quantifiers
+pub(crate) trait CostedScan {
+    type State: Clone + Eq + Hash;
+    // The states after reading `ch`, each with how many lazy quantifiers
+    // start another iteration on it, see `KyomuRegex::costed_derivative`.
+    // States no input leads to a match from may be left out.
+    fn costed_step(&mut self, state: &Self::State, ch: char) -> Vec<(Self::State, u32)>;
+    // whether `state` matches the empty string when `rest` follows
+    fn accepts(&mut self, state: &Self::State, rest: &str) -> bool;
+}
+
+impl CostedScan for Derivatives {
+    type State = KyomuRegex;
+    fn costed_step(&mut self, state: &KyomuRegex, ch: char) -> Vec<(KyomuRegex, u32)> {
+        let mut next = state.costed_derivative(ch);
+        next.retain(|(d, _)| *d != KyomuRegex::Empty);
+        next
+    }
+    fn accepts(&mut self, state: &KyomuRegex, rest: &str) -> bool {
+        state.delta().holds_on(rest)
+    }
+}
+
+// .*⋅reverse(regex): read backwards from i, it matches iff a match starts at i
+pub(crate) fn start_scan(regex: &KyomuRegex) -> KyomuRegex {
+    use KyomuRegex::*;
+    Concat(Box::new(Star(Box::new(Any))), Box::new(regex.reverse()))
+}
+
+// The first position where a match starts, in a single backward pass of
+// `backward` from `state`, which stands for `start_scan` of the regex
+pub(crate) fn leftmost_start<S: Scan>(backward: &mut S, mut state: S::State, haystack: &str) -> Option<usize> {
+    let reversed: String = haystack.chars().rev().collect();
+    // the rest of the reversed input is what comes before i
+    let mut first = backward.accepts(&state, &reversed).then_some(haystack.len());
+    for (j, ch) in reversed.char_indices() {
+        state = backward.step(&state, ch);
+        let j = j + ch.len_utf8();
+        if backward.accepts(&state, &reversed[j..]) {
+            first = Some(haystack.len() - j);
+        }
+    }
+    first
+}
+
+// The end of the longest match from `start`, where `forward` is in `state`
+pub(crate) fn longest_end<S: Scan>(forward: &mut S, mut state: S::State, haystack: &str, start: usize) -> Option<usize> {
+    let mut end = forward.accepts(&state, &haystack[start..]).then_some(start);
+    for (i, ch) in haystack[start..].char_indices() {
+        if forward.is_dead(&state) {
+            break;
+        }
+        state = forward.step(&state, ch);
+        let pos = start + i + ch.len_utf8();
+        if forward.accepts(&state, &haystack[pos..]) {
+            end = Some(pos);
+        }
+    }
+    end
+}
+
+// Where the match from `start` ends, where `forward` is in `state`. Each way
+// to match is charged, at every position, the lazy iterations it starts
+// there; the least charged way wins, compared position by position, then the
+// longest one. Without lazy quantifiers that is just `longest_end`.
+pub(crate) fn preferred_end<S: CostedScan>(forward: &mut S, state: S::State, haystack: &str, start: usize) -> Option<usize> {
+    // (state, rank of its charges so far), 0 being the least charged.
+    // The best match keeps its rank as if it went on uncharged.
+    let mut best = forward.accepts(&state, &haystack[start..]).then_some((start, 0));
+    let mut threads = vec![(state, 0)];
+    for (i, ch) in haystack[start..].char_indices() {
+        let pos = start + i + ch.len_utf8();
+        let mut next = vec![];
+        for (state, rank) in &threads {
+            for (s, cost) in forward.costed_step(state, ch) {
+                next.push((s, (*rank, cost)));
+            }
+        }
+        let mut keys: Vec<_> = next.iter().map(|(_, key)| *key).collect();
+        keys.extend(best.map(|(_, rank)| (rank, 0)));
+        keys.sort();
+        keys.dedup();
+        let rank_of = |key| keys.binary_search(&key).unwrap();
+        best = best.map(|(end, rank)| (end, rank_of((rank, 0))));
+        // the same state twice only goes on with the lesser charge
+        next.sort_by_key(|(_, key)| *key);
+        let mut seen = HashSet::new();
+        threads = next
+            .into_iter()
+            .map(|(s, key)| (s, rank_of(key)))
+            .filter(|(s, rank)| best.is_none_or(|(_, b)| *rank <= b) && seen.insert(s.clone()))
+            .collect();
+        let rest = &haystack[pos..];
+        let matched = threads.iter().filter(|(s, _)| forward.accepts(s, rest)).map(|(_, rank)| *rank).min();
+        if let Some(rank) = matched {
+            if best.is_none_or(|(_, b)| rank <= b) {
+                best = Some((pos, rank));
+                threads.retain(|(_, r)| *r <= rank);
+            }
+        }
+        if threads.is_empty() {
+            break;
+        }
+    }
+    best.map(|(end, _)| end)
+}
+
+// the largest bound of a counted repetition in `regex`
+fn largest_bound(regex: &KyomuRegex) -> u32 {
+    use KyomuRegex::*;
+    match regex {
+        Char(_) | Any | AnyExcept(_) | Eps | Empty => 0,
+        Repeat(min, max, r) => (*min).max(max.unwrap_or(0)).max(largest_bound(r)),
+        Concat(left, right) | Or(left, right) | And(left, right) => {
+            largest_bound(left).max(largest_bound(right))
+        }
+        LookBehind(r, _) | NegLookBehind(r, _) => largest_bound(r),
+        Star(r) | Plus(r) | Question(r) | Not(r) | Lazy(r) | LookAhead(r) | NegLookAhead(r) => {
+            largest_bound(r)
+        }
+    }
+}
+
+// The engine for Engine::Auto. Large counted repetitions, as in (a|b)*a(a|b){20},
+// can give the DFA exponentially many states, so they go to the NFA when it
+// supports the pattern. Anything else goes to the lazy DFA.
+// The threshold is a rule of thumb, not a measurement: (a|b)*a(a|b){n} has
+// 2^(n+1) DFA states, so n = 8 is about where a DFA walk would build
+// hundreds of states for a pattern whose NFA has a dozen. Patterns with a
+// blow-up of another kind (say (a|b)*a(a|b)(a|b)...) still get the lazy
+// DFA, whose cache is bounded by `LazyDfa::STATE_LIMIT`.
+pub(crate) fn choose(regex: &KyomuRegex) -> Engine {
+    if largest_bound(regex) > 8 && regex.has_partial_derivatives() {
+        Engine::Nfa
+    } else {
+        Engine::LazyDfa
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CompileError, KyomuRegexBuilder};
+
+    fn matcher(pattern: &str, engine: Engine) -> Box<dyn Matcher + Send> {
+        KyomuRegexBuilder::new(pattern)
+            .engine(engine)
+            .build_matcher()
+            .unwrap()
+    }
+
+    #[test]
+    fn engines_agree() {
+        let patterns = [
+            "a(b|c)*d?",
+            "<.+?>",
+            "<.+?>x*",
+            "a+?b+",
+            "(ab|b)*a",
+            "x{2,3}y*",
+            "é+",
+            "(a|b)*a(a|b){3}",
+            "()",
+        ];
+        let haystacks = [
+            "", "abcbd", "x<a><b>y", "bbaba", "xxxyy", "aébé", "abbbab", "\n", "<a>xx",
+        ];
+        for pattern in patterns {
+            let reference = KyomuRegex::compile(pattern).unwrap();
+            for engine in [
+                Engine::Auto,
+                Engine::Derivative,
+                Engine::LazyDfa,
+                Engine::Nfa,
+            ] {
+                let m = matcher(pattern, engine);
+                for haystack in haystacks {
+                    let what = format!("{} with {:?} on {:?}", pattern, engine, haystack);
+                    assert_eq!(
+                        m.whole_match(haystack),
+                        reference.whole_match(haystack),
+                        "{}",
+                        what
+                    );
+                    assert_eq!(m.find(haystack), reference.find(haystack), "{}", what);
+                    assert_eq!(
+                        m.is_match(haystack),
+                        reference.find(haystack).is_some(),
+                        "{}",
+                        what
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lazy_dfa_with_lookarounds() {
+        for pattern in ["(?<=a)b+", "a(?!b).", "(a|b)*--.*bb.*", "(?<!x)y(?=z)"] {
+            let reference = KyomuRegex::compile(pattern).unwrap();
+            let dfa = LazyDfa::new(&reference);
+            for haystack in ["abbb", "bab", "acab", "ababb", "xyz yz", "yz"] {
+                assert_eq!(
+                    dfa.whole_match(haystack),
+                    reference.whole_match(haystack),
+                    "{}",
+                    pattern
+                );
+                assert_eq!(
+                    dfa.find(haystack),
+                    reference.find(haystack),
+                    "{} in {:?}",
+                    pattern,
+                    haystack
+                );
+            }
+        }
+        // states are built once and reused
+        let dfa = LazyDfa::new(&KyomuRegex::compile("(ab)*").unwrap());
+        dfa.whole_match("abababab");
+        let built = dfa.states_built();
+        dfa.whole_match("abababababab");
+        assert_eq!(dfa.states_built(), built);
+        // an exponential DFA stays within the limit, with the same answers
+        let reference = KyomuRegex::compile("(a|b)*a(a|b){6}").unwrap();
+        let dfa = LazyDfa::with_state_limit(&reference, 20);
+        let mut rng = crate::Rng::new(3);
+        for _ in 0..20 {
+            let haystack: String = (0..40).map(|_| ['a', 'b'][rng.below(2)]).collect();
+            assert_eq!(dfa.whole_match(&haystack), reference.whole_match(&haystack));
+            assert_eq!(dfa.find(&haystack), reference.find(&haystack));
+            assert!(dfa.states_built() <= 40);
+        }
+        // lazy quantifiers, on charged derivatives kept within the limit too
+        let reference = KyomuRegex::compile("(a|b)*?a(a|b){4}?b*?").unwrap();
+        let dfa = LazyDfa::with_state_limit(&reference, 20);
+        for _ in 0..20 {
+            let haystack: String = (0..40).map(|_| ['a', 'b'][rng.below(2)]).collect();
+            assert_eq!(dfa.find(&haystack), reference.find(&haystack));
+            assert!(dfa.states_built() <= 60);
+        }
+    }
+
+    #[test]
+    fn lazy_dfa_with_lazy_quantifiers() {
+        for pattern in ["<.+?>", "a*?b??", "(a|ab)+?b*", "(?<=a)b{1,3}?", "(b+?a|b)*c?"] {
+            let reference = KyomuRegex::compile(pattern).unwrap();
+            let dfa = LazyDfa::new(&reference);
+            for haystack in ["<a><b>", "aabb", "abab", "abbb", "bbabc", ""] {
+                assert_eq!(dfa.find(haystack), reference.find(haystack), "{} in {:?}", pattern, haystack);
+                // the second time from the cache
+                assert_eq!(dfa.find(haystack), reference.find(haystack), "{} in {:?}", pattern, haystack);
+            }
+        }
+    }
+
+    #[test]
+    fn choice() {
+        let choose = |pattern| choose(&KyomuRegex::compile(pattern).unwrap());
+        assert_eq!(choose("(a|b)*a(a|b){20}"), Engine::Nfa);
+        assert_eq!(choose("(a|b)*a(a|b){2}"), Engine::LazyDfa);
+        assert_eq!(choose("(?=a)(a|b){20}"), Engine::LazyDfa);
+        let error = KyomuRegexBuilder::new("a*--b")
+            .engine(Engine::Nfa)
+            .build_matcher()
+            .err();
+        assert_eq!(error, Some(CompileError::UnsupportedByNfa { span: 0..5 }));
+    }
+}
@@ -0,0 +1,201 @@
+use std::fmt::Display;
+
+// byte range in the pattern
+pub type Span = std::ops::Range<usize>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CompileError {
+    // a token the grammar does not allow here
+    UnexpectedToken {
+        expected: Vec<TokenKind>,
+        found: TokenKind,
+        span: Span,
+    },
+    // '(' without ')' or ')' without '('
+    UnbalancedParen { span: Span },
+    // '[' without ']'
+    UnbalancedBracket { span: Span },
+    // a range such as [a-z], which classes do not have
+    ClassRange { span: Span },
+    // malformed {min,max}
+    BadRepetition { kind: RepetitionError, span: Span },
+    // unknown or malformed escape sequence
+    InvalidEscape { escape: String, span: Span },
+    // unknown letter in (?flags)
+    UnknownFlag { flag: char, span: Span },
+    // flag both set and cleared, as in (?i-i)
+    ConflictingFlag { flag: char, span: Span },
+    // no flag where one is needed, as in (?) or (?i-)
+    MissingFlag { span: Span },
+    // lookaround inside another lookaround
+    NestedLookaround { span: Span },
+    // & ~ or a lookaround, with the NFA engine
+    UnsupportedByNfa { span: Span },
+}
+
+// A token of a pattern, as UnexpectedToken names it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    Char(char),                  // a literal character
+    AnyChar,                     // expected: some literal character
+    Dot,                         // .
+    ClassOpen { negated: bool }, // [ or [^
+    ClassClose,                  // ]
+    Or,                          // |
+    Diff,                        // --
+    Star,                        // *
+    Plus,                        // +
+    Question,                    // ?
+    // {min,max}, followed by ? if lazy
+    Repeat { min: u32, max: Option<u32>, lazy: bool },
+    LazyStar,                    // *?
+    LazyPlus,                    // +?
+    LazyQuestion,                // ??
+    Lparen,                      // (
+    Rparen,                      // )
+    SetFlags,                    // (?i-s)
+    FlagGroup,                   // (?i-s: or (?:
+    LookAhead,                   // (?=
+    NegLookAhead,                // (?!
+    LookBehind,                  // (?<=
+    NegLookBehind,               // (?<!
+    End,                         // the end of the pattern
+}
+
+impl Display for TokenKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use TokenKind::*;
+        let str = match self {
+            Char(c) => return write!(f, "'{}'", c),
+            AnyChar => return write!(f, "a character"),
+            End => return write!(f, "end of pattern"),
+            Repeat { min, max, lazy } => {
+                let lazy = if *lazy { "?" } else { "" };
+                return match max {
+                    Some(max) if max == min => write!(f, "'{{{}}}{}'", min, lazy),
+                    Some(max) => write!(f, "'{{{},{}}}{}'", min, max, lazy),
+                    None => write!(f, "'{{{},}}{}'", min, lazy),
+                };
+            }
+            Dot => ".",
+            ClassOpen { negated: false } => "[",
+            ClassOpen { negated: true } => "[^",
+            ClassClose => "]",
+            Or => "|",
+            Diff => "--",
+            Star => "*",
+            Plus => "+",
+            Question => "?",
+            LazyStar => "*?",
+            LazyPlus => "+?",
+            LazyQuestion => "??",
+            Lparen => "(",
+            Rparen => ")",
+            SetFlags => "(?flags)",
+            FlagGroup => "(?flags:",
+            LookAhead => "(?=",
+            NegLookAhead => "(?!",
+            LookBehind => "(?<=",
+            NegLookBehind => "(?<!",
+        };
+        write!(f, "'{}'", str)
+    }
+}
+
+// What is wrong with a {min,max} repetition, or with a quantifier on another.
+// A '{' always starts a repetition; write '\{' for a literal brace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepetitionError {
+    InvalidContent, // anything but digits and a single ','
+    Unterminated,   // no closing '}'
+    MinExceedsMax,  // e.g. {4,2}
+    Overflow,       // a bound does not fit in u32
+    Repeated,       // on a quantifier, as in a** or x{2}{3}
+}
+
+impl Display for RepetitionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use RepetitionError::*;
+        let str = match self {
+            InvalidContent => "expected {n}, {n,}, {,m} or {n,m}",
+            Unterminated => "missing '}'",
+            MinExceedsMax => "minimum exceeds maximum",
+            Overflow => "bound is too large",
+            Repeated => "a quantifier cannot be repeated",
+        };
+        write!(f, "{}", str)
+    }
+}
+
+impl CompileError {
+    pub fn span(&self) -> Span {
+        use CompileError::*;
+        match self {
+            UnexpectedToken { span, .. }
+            | UnbalancedParen { span }
+            | UnbalancedBracket { span }
+            | ClassRange { span }
+            | BadRepetition { span, .. }
+            | InvalidEscape { span, .. }
+            | UnknownFlag { span, .. }
+            | ConflictingFlag { span, .. }
+            | MissingFlag { span }
+            | NestedLookaround { span }
+            | UnsupportedByNfa { span } => span.clone(),
+        }
+    }
+}
+
+impl Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use CompileError::*;
+        match self {
+            UnexpectedToken { expected, found, .. } => {
+                let expected: Vec<String> = expected.iter().map(|kind| kind.to_string()).collect();
+                write!(f, "Expected one of [{}], found {}", expected.join(", "), found)?
+            }
+            UnbalancedParen { .. } => write!(f, "Unbalanced parenthesis")?,
+            UnbalancedBracket { .. } => write!(f, "Unterminated character class")?,
+            ClassRange { .. } => write!(f, "Ranges in character classes are not supported; write \\- for '-'")?,
+            BadRepetition { kind, .. } => write!(f, "Malformed repetition: {}", kind)?,
+            InvalidEscape { escape, .. } => write!(f, "Invalid escape '{}'", escape)?,
+            UnknownFlag { flag, .. } => write!(f, "Unknown flag '{}'", flag)?,
+            ConflictingFlag { flag, .. } => write!(f, "Flag '{}' is both set and cleared", flag)?,
+            MissingFlag { .. } => write!(f, "Expected a flag")?,
+            NestedLookaround { .. } => write!(f, "Nested lookaround is not supported")?,
+            UnsupportedByNfa { .. } => write!(f, "{}", NfaError)?,
+        }
+        let span = self.span();
+        write!(f, " at {}..{}", span.start, span.end)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// From `Nfa::new`: the regex has & ~ (other than in sets of characters) or a
+// lookaround. A regex keeps no pattern text, so there is no span;
+// `KyomuRegexBuilder::build_matcher` turns it into a CompileError.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NfaError;
+
+impl Display for NfaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The NFA engine does not support & ~ or lookarounds")
+    }
+}
+
+impl std::error::Error for NfaError {}
+
+// From `Dfa::new`: the automaton would have more than `limit` states
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DfaError {
+    pub limit: usize,
+}
+
+impl Display for DfaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The DFA has more than {} states", self.limit)
+    }
+}
+
+impl std::error::Error for DfaError {}
@@ -0,0 +1,65 @@
+use crate::fold_table::SIMPLE_FOLDS;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// Simple (one-to-one) case folding, from CaseFolding.txt
+fn simple_fold(ch: char) -> char {
+    match SIMPLE_FOLDS.binary_search_by_key(&ch, |&(c, _)| c) {
+        Ok(i) => SIMPLE_FOLDS[i].1,
+        Err(_) => ch,
+    }
+}
+
+// fold -> every character folding to it, for the non-trivial classes only
+fn orbits() -> &'static HashMap<char, Vec<char>> {
+    static ORBITS: OnceLock<HashMap<char, Vec<char>>> = OnceLock::new();
+    ORBITS.get_or_init(|| {
+        let mut orbits: HashMap<char, Vec<char>> = HashMap::new();
+        for &(ch, folded) in &SIMPLE_FOLDS {
+            orbits.entry(folded).or_insert_with(|| vec![folded]).push(ch);
+        }
+        orbits
+    })
+}
+
+// All characters equal to `ch` under simple case folding, `ch` first
+pub fn case_orbit(ch: char) -> Vec<char> {
+    let mut orbit = vec![ch];
+    if let Some(chars) = orbits().get(&simple_fold(ch)) {
+        orbit.extend(chars.iter().filter(|&&c| c != ch));
+    }
+    orbit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii_orbit() {
+        assert_eq!(case_orbit('a'), vec!['a', 'A']);
+        assert_eq!(case_orbit('A'), vec!['A', 'a']);
+        assert_eq!(case_orbit('1'), vec!['1']);
+    }
+
+    #[test]
+    fn unicode_orbit() {
+        // KELVIN SIGN folds to 'k'
+        let mut orbit = case_orbit('k');
+        orbit.sort();
+        assert_eq!(orbit, vec!['K', 'k', '\u{212A}']);
+        // LATIN SMALL LETTER LONG S folds to 's'
+        assert!(case_orbit('S').contains(&'\u{017F}'));
+        assert!(case_orbit('σ').contains(&'ς'));
+        // CAPITAL SHARP S folds to ß only by the simple (S) mapping
+        assert_eq!(case_orbit('ß'), vec!['ß', '\u{1E9E}']);
+    }
+
+    #[test]
+    fn turkic_i() {
+        // dotless and dotted I only fold to i in Turkic (T) mappings
+        assert_eq!(case_orbit('i'), vec!['i', 'I']);
+        assert_eq!(case_orbit('\u{131}'), vec!['\u{131}']);
+        assert_eq!(case_orbit('\u{130}'), vec!['\u{130}']);
+    }
+}
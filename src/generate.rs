@@ -0,0 +1,376 @@
+// Producing strings from the language of a regex
+use crate::dfa::{CharClass, Dfa};
+use crate::{DfaError, KyomuRegex};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+// Small seeded pseudo-random generator (xorshift64*).
+// The same seed always gives the same sequence.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // splitmix64 of the seed, so that 0 and nearby seeds also work
+        let mut z = seed.wrapping_add(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        Rng((z ^ (z >> 31)).max(1))
+    }
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+    // Uniform in 0..n. Panics if `n` is 0, as no number is below it.
+    pub fn below(&mut self, n: usize) -> usize {
+        assert!(n > 0, "Rng::below(0): the range 0..0 is empty");
+        (self.next_u64() % n as u64) as usize
+    }
+}
+
+// a random character of `class`, printable ASCII when possible
+fn pick(class: &CharClass, rng: &mut Rng) -> char {
+    match class {
+        CharClass::Char(c) => *c,
+        CharClass::Other(_) => {
+            let printable: Vec<char> = (' '..='~').filter(|&c| class.contains(c)).collect();
+            match printable.len() {
+                0 => class.representative(),
+                n => printable[rng.below(n)],
+            }
+        }
+    }
+}
+
+// Random matching strings of at most `max_len` characters, from
+// `KyomuRegex::generator`. The automaton and the tables of what it can still
+// do are built once, so drawing many strings costs only the walks.
+// The tables stop where they repeat, so a large `max_len` costs no more.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    dfa: Dfa,
+    classes: Vec<CharClass>,
+    max_len: usize,
+    // [k][state]: some match of length at most k, up to the k where it stops
+    // changing, at most the number of states
+    within: Vec<Vec<bool>>,
+    // [k][state]: some match of length exactly k, until a row comes back:
+    // from `cycle` on the rows repeat
+    exactly: Vec<Vec<bool>>,
+    cycle: usize,
+}
+
+impl Generator {
+    fn new(dfa: Dfa, max_len: usize) -> Self {
+        let states = 0..dfa.states().len();
+        let accepting: Vec<bool> = states.clone().map(|s| dfa.is_accepting(s)).collect();
+        let step = |table: &[bool]| -> Vec<bool> {
+            states.clone().map(|s| dfa.transitions(s).iter().any(|&t| table[t])).collect()
+        };
+        let mut within = vec![accepting.clone()];
+        while within.len() <= max_len {
+            let last = within.last().unwrap();
+            let next: Vec<bool> = step(last).iter().zip(last).map(|(a, b)| *a || *b).collect();
+            if next == *last {
+                break;
+            }
+            within.push(next);
+        }
+        let mut seen = HashMap::from([(accepting.clone(), 0)]);
+        let mut exactly = vec![accepting];
+        let mut cycle = 1;
+        while exactly.len() <= max_len {
+            let next = step(exactly.last().unwrap());
+            if let Some(&k) = seen.get(&next) {
+                cycle = k;
+                break;
+            }
+            seen.insert(next.clone(), exactly.len());
+            exactly.push(next);
+            cycle = exactly.len();
+        }
+        let classes = dfa.classes();
+        Generator { dfa, classes, max_len, within, exactly, cycle }
+    }
+
+    fn within(&self, k: usize) -> &[bool] {
+        &self.within[k.min(self.within.len() - 1)]
+    }
+    fn exactly(&self, k: usize) -> &[bool] {
+        let rows = self.exactly.len();
+        if k < rows {
+            &self.exactly[k]
+        } else {
+            &self.exactly[self.cycle + (k - self.cycle) % (rows - self.cycle)]
+        }
+    }
+
+    // Walks from the initial state, choosing uniformly among the character
+    // classes (and stopping, when `exact` allows it) that can still lead to a
+    // match of the wanted length.
+    fn walk(&self, rng: &mut Rng, len: usize, exact: bool) -> String {
+        let (mut state, mut word) = (0, String::new());
+        for left in (1..=len).rev() {
+            let row = self.dfa.transitions(state);
+            let table = if exact { self.exactly(left - 1) } else { self.within(left - 1) };
+            let stop = !exact && self.dfa.is_accepting(state);
+            let moves: Vec<usize> = (0..row.len()).filter(|&i| table[row[i]]).collect();
+            let choice = rng.below(moves.len() + stop as usize);
+            if choice == moves.len() {
+                break;
+            }
+            word.push(pick(&self.classes[moves[choice]], rng));
+            state = row[moves[choice]];
+        }
+        word
+    }
+
+    // Same as `KyomuRegex::generate`
+    pub fn generate(&self, rng: &mut Rng) -> Option<String> {
+        self.within(self.max_len)[0].then(|| self.walk(rng, self.max_len, false))
+    }
+    // Same as `KyomuRegex::generate_by_length`
+    pub fn generate_by_length(&self, rng: &mut Rng) -> Option<String> {
+        // the lengths with a match: some before the cycle, then the same
+        // offsets in every turn of it up to `max_len`
+        let rows = self.exactly.len();
+        let last = self.max_len.min(rows - 1);
+        let before: Vec<usize> = (0..self.cycle.min(last + 1)).filter(|&k| self.exactly[k][0]).collect();
+        let offsets: Vec<usize> = (self.cycle..rows).filter(|&k| self.exactly[k][0]).map(|k| k - self.cycle).collect();
+        let (period, turns) = match self.max_len.checked_sub(self.cycle) {
+            Some(n) => (rows - self.cycle, n as u128 + 1),
+            None => (1, 0),
+        };
+        let in_cycle = |count: u128| {
+            let (full, rest) = (count / period as u128, count % period as u128);
+            full * offsets.len() as u128 + offsets.iter().filter(|&&o| (o as u128) < rest).count() as u128
+        };
+        let total = before.len() as u128 + in_cycle(turns);
+        if total == 0 {
+            return None;
+        }
+        let i = rng.next_u64() as u128 % total;
+        let len = match i.checked_sub(before.len() as u128) {
+            None => before[i as usize],
+            Some(j) => {
+                let (turn, o) = (j / offsets.len() as u128, offsets[(j % offsets.len() as u128) as usize]);
+                self.cycle + (turn * period as u128) as usize + o
+            }
+        };
+        Some(self.walk(rng, len, true))
+    }
+}
+
+impl KyomuRegex {
+    // A random matching string of at most `max_len` characters, or None if there is none.
+    // Each step picks among the moves that can still reach a match, so the walk
+    // never runs into ∅. Short strings are the most likely.
+    // Fails when the automaton is too large, see `Dfa::new`.
+    // Use `generator` to draw many strings without rebuilding the automaton.
+    pub fn generate(&self, rng: &mut Rng, max_len: usize) -> Result<Option<String>, DfaError> {
+        Ok(self.generator(max_len)?.generate(rng))
+    }
+    // Like `generate`, but first picks the length uniformly among those with a
+    // match, so a huge `max_len` means huge strings
+    pub fn generate_by_length(&self, rng: &mut Rng, max_len: usize) -> Result<Option<String>, DfaError> {
+        Ok(self.generator(max_len)?.generate_by_length(rng))
+    }
+    // Prepared `generate` and `generate_by_length` for strings of at most `max_len` characters
+    pub fn generator(&self, max_len: usize) -> Result<Generator, DfaError> {
+        Ok(Generator::new(Dfa::new(self)?, max_len))
+    }
+}
+
+// Matching strings over an alphabet in shortlex order, from `KyomuRegex::strings`
+#[derive(Debug, Clone)]
+pub struct Strings {
+    alphabet: Vec<char>,
+    max_len: usize,
+    queue: VecDeque<(KyomuRegex, String, usize)>,
+    // whether a derivative matches no string over the alphabet, in which case
+    // it is not searched
+    dead: HashMap<KyomuRegex, bool>,
+}
+
+impl Iterator for Strings {
+    type Item = String;
+    fn next(&mut self) -> Option<String> {
+        // Breadth-first, with children in alphabet order: every string is
+        // visited after all shorter ones and all smaller ones of its length.
+        while let Some((r, word, len)) = self.queue.pop_front() {
+            if len < self.max_len {
+                for &ch in &self.alphabet {
+                    let next = r.derivative(ch);
+                    let alphabet = &self.alphabet;
+                    let dead = self.dead.entry(next.clone());
+                    if !*dead.or_insert_with(|| !matches_over(&next, alphabet)) {
+                        let mut word = word.clone();
+                        word.push(ch);
+                        self.queue.push_back((next, word, len + 1));
+                    }
+                }
+            }
+            if r.match_eps() {
+                return Some(word);
+            }
+        }
+        None
+    }
+}
+
+// Whether some string made of `alphabet` matches `regex`. Other characters may
+// be needed for a match, so `is_empty` is not enough.
+fn matches_over(regex: &KyomuRegex, alphabet: &[char]) -> bool {
+    let mut seen = HashSet::from([regex.clone()]);
+    let mut queue = VecDeque::from([regex.clone()]);
+    while let Some(r) = queue.pop_front() {
+        if r.match_eps() {
+            return true;
+        }
+        for &ch in alphabet {
+            let next = r.derivative(ch);
+            if next != KyomuRegex::Empty && seen.insert(next.clone()) {
+                queue.push_back(next);
+            }
+        }
+    }
+    false
+}
+
+impl KyomuRegex {
+    // Every matching string of at most `max_len` characters from `alphabet`,
+    // shortest first and then in lexicographic order
+    pub fn strings(&self, alphabet: &[char], max_len: usize) -> Strings {
+        let mut alphabet = alphabet.to_vec();
+        alphabet.sort();
+        alphabet.dedup();
+        Strings {
+            alphabet,
+            max_len,
+            queue: VecDeque::from([(self.clone(), String::new(), 0)]),
+            dead: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reproducible() {
+        let regex = KyomuRegex::compile("(ab|c.)*d?").unwrap();
+        let sample = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..20).map(|_| regex.generate(&mut rng, 8).unwrap().unwrap()).collect::<Vec<_>>()
+        };
+        assert_eq!(sample(42), sample(42));
+        assert_ne!(sample(42), sample(43));
+    }
+
+    #[test]
+    fn samples_match() {
+        let mut rng = Rng::new(0);
+        for pattern in ["(ab|c.)*d?", "a{3,5}--aaaa", "(a|b)*(?<=ab)", "x(?=y)..|z+", "(?i)k(?s:.)"] {
+            let regex = KyomuRegex::compile(pattern).unwrap();
+            for _ in 0..50 {
+                for input in [regex.generate(&mut rng, 6).unwrap(), regex.generate_by_length(&mut rng, 6).unwrap()] {
+                    let input = input.unwrap();
+                    assert!(input.chars().count() <= 6, "{} gave {:?}", pattern, input);
+                    assert!(regex.whole_match(&input), "{} gave {:?}", pattern, input);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn prepared() {
+        let regex = KyomuRegex::compile("(ab|c.)*d?").unwrap();
+        let generator = regex.generator(8).unwrap();
+        let (mut once, mut each) = (Rng::new(5), Rng::new(5));
+        for _ in 0..20 {
+            assert_eq!(generator.generate(&mut once), regex.generate(&mut each, 8).unwrap());
+            assert_eq!(generator.generate_by_length(&mut once), regex.generate_by_length(&mut each, 8).unwrap());
+        }
+    }
+
+    #[test]
+    fn no_match() {
+        let mut rng = Rng::new(0);
+        assert_eq!(KyomuRegex::compile("a--a").unwrap().generate(&mut rng, 10), Ok(None));
+        assert_eq!(KyomuRegex::compile("a{4}").unwrap().generate(&mut rng, 3), Ok(None));
+        assert_eq!(KyomuRegex::compile("a{4}").unwrap().generate_by_length(&mut rng, 3), Ok(None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Rng::below(0)")]
+    fn below_zero() {
+        Rng::new(0).below(0);
+    }
+
+    #[test]
+    fn too_many_states() {
+        // 2^15 states, past the limit
+        let regex = KyomuRegex::compile("(a|b)*a(a|b){14}").unwrap();
+        let error = DfaError { limit: Dfa::STATE_LIMIT };
+        assert_eq!(regex.generate(&mut Rng::new(0), 20), Err(error));
+        assert!(regex.generator(20).is_err());
+    }
+
+    #[test]
+    fn uniform_by_length() {
+        let regex = KyomuRegex::compile("a*").unwrap();
+        let mut rng = Rng::new(7);
+        let mut seen = [0; 11];
+        for _ in 0..1100 {
+            seen[regex.generate_by_length(&mut rng, 10).unwrap().unwrap().len()] += 1;
+        }
+        assert!(seen.iter().all(|&n| n > 50), "{:?}", seen);
+    }
+
+    #[test]
+    fn unbounded_length() {
+        // the tables stop where they repeat rather than at max_len
+        let mut rng = Rng::new(3);
+        let regex = KyomuRegex::compile("x(ab|c.)*d?").unwrap();
+        let generator = regex.generator(usize::MAX).unwrap();
+        assert!(generator.within.len() <= generator.dfa.states().len());
+        assert!(generator.exactly.len() <= generator.dfa.states().len());
+        for _ in 0..20 {
+            assert!(regex.whole_match(&regex.generate(&mut rng, usize::MAX).unwrap().unwrap()));
+        }
+        // lengths 1, 3, 5, ... 99 then repeat with period 2 from the start
+        let regex = KyomuRegex::compile("(aa)*a").unwrap();
+        let mut seen = [0; 100];
+        for _ in 0..2000 {
+            let len = regex.generate_by_length(&mut rng, 100).unwrap().unwrap().len();
+            assert_eq!(len % 2, 1);
+            seen[len] += 1;
+        }
+        assert!((1..100).step_by(2).all(|k| seen[k] > 10), "{:?}", seen);
+        let regex = KyomuRegex::compile("aaa(b{3})*").unwrap();
+        for _ in 0..20 {
+            let sample = regex.generate_by_length(&mut rng, 1000).unwrap().unwrap();
+            assert!(sample.len() <= 1000 && regex.whole_match(&sample), "{}", sample);
+        }
+    }
+
+    #[test]
+    fn shortlex() {
+        let strings = |pattern, alphabet: &[char], max_len| {
+            KyomuRegex::compile(pattern).unwrap().strings(alphabet, max_len).collect::<Vec<_>>()
+        };
+        assert_eq!(strings("a*b?", &['b', 'a'], 2), ["", "a", "b", "aa", "ab"]);
+        assert_eq!(strings("(a|b)*--.*a.*", &['a', 'b'], 3), ["", "b", "bb", "bbb"]);
+        assert_eq!(strings("x(?=y).", &['x', 'y', 'z'], 5), ["xy"]);
+        assert_eq!(strings("c+", &['a', 'b'], 10), Vec::<String>::new());
+        assert_eq!(strings("a{2,}", &['a'], 4), ["aa", "aaa", "aaaa"]);
+        // b is needed but not offered
+        assert_eq!(KyomuRegex::compile("a*b").unwrap().strings(&['a'], usize::MAX).next(), None);
+        // lazily, with no length cap to speak of
+        let regex = KyomuRegex::compile(".*").unwrap();
+        let first: Vec<String> = regex.strings(&['1', '0'], usize::MAX).take(4).collect();
+        assert_eq!(first, ["", "0", "1", "00"]);
+    }
+}
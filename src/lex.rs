@@ -1,94 +1,381 @@
 use std::str::Chars;
-use std::fmt::Display;
+use crate::error::{CompileError, RepetitionError, Span, TokenKind};
+type Result<T> = std::result::Result<T, CompileError>;
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Flags {
+    pub case_insensitive: bool,     // i
+    pub dot_matches_new_line: bool, // s
+    pub verbose: bool,              // x
+}
+
+impl Flags {
+    // flags set in either `self` or `other`
+    pub fn union(self, other: Flags) -> Flags {
+        Flags {
+            case_insensitive: self.case_insensitive || other.case_insensitive,
+            dot_matches_new_line: self.dot_matches_new_line || other.dot_matches_new_line,
+            verbose: self.verbose || other.verbose,
+        }
+    }
+    // flags set in `self` but not in `other`
+    pub fn minus(self, other: Flags) -> Flags {
+        Flags {
+            case_insensitive: self.case_insensitive && !other.case_insensitive,
+            dot_matches_new_line: self.dot_matches_new_line && !other.dot_matches_new_line,
+            verbose: self.verbose && !other.verbose,
+        }
+    }
+}
+
+// flags turned on and off by (?on-off) or (?on-off:...)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct FlagDelta {
+    pub on: Flags,
+    pub off: Flags,
+}
+
+impl FlagDelta {
+    pub fn apply(self, flags: Flags) -> Flags {
+        flags.minus(self.off).union(self.on)
+    }
+    // `self` followed by `next`
+    pub fn then(self, next: FlagDelta) -> FlagDelta {
+        FlagDelta {
+            on: self.on.minus(next.off).union(next.on),
+            off: self.off.minus(next.on).union(next.off),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Token {
     TkChar(char),
+    TkAny, // .
+    TkClassOpen(bool), // [, or [^ when negated
+    TkClassClose,      // ]
     TkOr,
     TkStar,
     TkPlus,
     TkQuestion,
     TkLparen,
     TkRparen,
-    TkBracket(u32, Option<u32>),  // max == none means unbounded
+    TkRepeat(u32, Option<u32>),  // {min,max}; max == None means unbounded
+    TkLazyStar,
+    TkLazyPlus,
+    TkLazyQuestion,
+    TkLazyRepeat(u32, Option<u32>),
+    TkDiff,
+    TkSetFlags(FlagDelta),  // (?i-s)
+    TkFlagGroup(FlagDelta), // (?i-s: or (?:
+    TkLookAhead,            // (?=
+    TkNegLookAhead,         // (?!
+    TkLookBehind,           // (?<=
+    TkNegLookBehind,        // (?<!
     TkEps
 }
 
 pub struct Lexer<'a> {
-    string: Chars<'a>
+    source: &'a str,
+    string: Chars<'a>,
+    start: usize,      // where the last token starts
+    verbose: bool,     // skip whitespace and # comments
+    class: Option<usize>, // between [ and ]: where its characters start
+    scopes: Vec<bool>, // verbose mode outside each open group
 }
 
-impl Display for Token {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+// what `CompileError::UnexpectedToken` says of a token
+impl From<Token> for TokenKind {
+    fn from(token: Token) -> Self {
         use Token::*;
-        let str = match self {
-            TkChar(_) => "Char",
-            TkOr => "|",
-            TkStar => "*",
-            TkPlus => "+",
-            TkQuestion => "?",
-            TkLparen => "(",
-            TkRparen => ")",
-            TkBracket {..} => "Bracket",
-            TkEps => "ε",
-        };
-        write!(f, "{}", str)
+        match token {
+            TkChar(c) => TokenKind::Char(c),
+            TkAny => TokenKind::Dot,
+            TkClassOpen(negated) => TokenKind::ClassOpen { negated },
+            TkClassClose => TokenKind::ClassClose,
+            TkOr => TokenKind::Or,
+            TkStar => TokenKind::Star,
+            TkPlus => TokenKind::Plus,
+            TkQuestion => TokenKind::Question,
+            TkLparen => TokenKind::Lparen,
+            TkRparen => TokenKind::Rparen,
+            TkRepeat(min, max) => TokenKind::Repeat { min, max, lazy: false },
+            TkLazyStar => TokenKind::LazyStar,
+            TkLazyPlus => TokenKind::LazyPlus,
+            TkLazyQuestion => TokenKind::LazyQuestion,
+            TkLazyRepeat(min, max) => TokenKind::Repeat { min, max, lazy: true },
+            TkDiff => TokenKind::Diff,
+            TkSetFlags(_) => TokenKind::SetFlags,
+            TkFlagGroup(_) => TokenKind::FlagGroup,
+            TkLookAhead => TokenKind::LookAhead,
+            TkNegLookAhead => TokenKind::NegLookAhead,
+            TkLookBehind => TokenKind::LookBehind,
+            TkNegLookBehind => TokenKind::NegLookBehind,
+            TkEps => TokenKind::End,
+        }
+    }
+}
+
+// Code point written as `len` hex digits
+fn hex_char(hex: &str, len: std::ops::RangeInclusive<usize>) -> Option<char> {
+    if !len.contains(&hex.len()) || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
     }
+    char::from_u32(u32::from_str_radix(hex, 16).ok()?)
 }
 
 impl Lexer<'_> {
     pub fn new(string: &str) -> Lexer<'_> {
         Lexer {
-            string: string.chars()
+            source: string,
+            string: string.chars(),
+            start: 0,
+            verbose: false,
+            class: None,
+            scopes: Vec::new(),
         }
     }
-    pub fn next_token(&mut self) -> Token {
+    pub fn with_flags(mut self, flags: Flags) -> Self {
+        self.verbose = flags.verbose;
+        self
+    }
+    // byte offset of the next character
+    fn pos(&self) -> usize {
+        self.source.len() - self.string.as_str().len()
+    }
+    // span of the last token
+    pub fn span(&self) -> Span {
+        self.start..self.pos()
+    }
+    pub fn next_token(&mut self) -> Result<Token> {
         use Token::*;
+        self.start = self.pos();
         let Some(ch) = self.string.next() else {
-            return TkEps;
+            return Ok(TkEps);
         };
-        match ch {
-            '\\' => TkChar(self.string.next().unwrap_or('\\')), // escape character
+        if let Some(first) = self.class {
+            // every character stands for itself, whitespace too
+            return match ch {
+                ']' => {
+                    self.class = None;
+                    Ok(TkClassClose)
+                }
+                '\\' => Ok(TkChar(self.next_escape()?)),
+                // a-z would be a range, which is not supported; '-' is only
+                // a character first, last or escaped
+                '-' if self.start != first && !matches!(self.string.clone().next(), Some(']') | None) => {
+                    Err(CompileError::ClassRange { span: self.span() })
+                }
+                c => Ok(TkChar(c)),
+            };
+        }
+        let token = match ch {
+            '\\' => TkChar(self.next_escape()?), // escape character
             '|' => TkOr,
-            '(' => TkLparen,
-            ')' => TkRparen,
+            '(' if self.string.clone().next() == Some('?') => {
+                self.string.next();
+                self.next_token_with_question()?
+            }
+            '(' => {
+                self.scopes.push(self.verbose);
+                TkLparen
+            }
+            ')' => {
+                self.verbose = self.scopes.pop().unwrap_or(self.verbose);
+                TkRparen
+            }
+            '*' if self.eat_lazy() => TkLazyStar,
             '*' => TkStar,
+            '+' if self.eat_lazy() => TkLazyPlus,
             '+' => TkPlus,
+            '?' if self.eat_lazy() => TkLazyQuestion,
             '?' => TkQuestion,
-            '{' => self.next_token_with_bracket(), 
-            ' ' | '\n' | '\t' => self.next_token(), // skip whitespace
+            '{' => match self.next_token_with_bracket()? {
+                TkRepeat(min, max) if self.eat_lazy() => TkLazyRepeat(min, max),
+                token => token,
+            },
+            // "--" is reserved for difference, even inside words like <!--;
+            // a literal one is written -\- or \-\-
+            '-' if self.string.clone().next() == Some('-') => {
+                self.string.next();
+                TkDiff
+            }
+            c if self.verbose && c.is_whitespace() => return self.next_token(),
+            '#' if self.verbose => {
+                // comment to end of line
+                self.string.by_ref().find(|&c| c == '\n');
+                return self.next_token();
+            }
+            '.' => TkAny,
+            '[' => {
+                let negated = self.eat('^');
+                self.class = Some(self.pos());
+                TkClassOpen(negated)
+            }
             _ => TkChar(ch)
+        };
+        Ok(token)
+    }
+
+    fn next_escape(&mut self) -> Result<char> {
+        let ch = match self.string.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('x') => {
+                // \xHH
+                let hex: String = self.string.by_ref().take(2).collect();
+                hex_char(&hex, 2..=2).ok_or_else(|| self.invalid_escape())?
+            }
+            Some('u') => {
+                // \u{HHHH}
+                if self.string.next() != Some('{') {
+                    return Err(self.invalid_escape());
+                }
+                let mut hex = String::new();
+                loop {
+                    match self.string.next() {
+                        Some('}') => break,
+                        Some(c) => hex.push(c),
+                        None => return Err(self.invalid_escape()),
+                    }
+                }
+                hex_char(&hex, 1..=6).ok_or_else(|| self.invalid_escape())?
+            }
+            // metacharacters and other punctuation stand for themselves
+            Some(c) if c.is_ascii_punctuation() || c == ' ' => c,
+            _ => return Err(self.invalid_escape()),
+        };
+        Ok(ch)
+    }
+
+    // the escape sequence read so far
+    fn invalid_escape(&self) -> CompileError {
+        CompileError::InvalidEscape {
+            escape: self.source[self.span()].to_string(),
+            span: self.span(),
+        }
+    }
+
+    // consume a '?' following a quantifier, which makes it lazy
+    fn eat_lazy(&mut self) -> bool {
+        self.eat('?')
+    }
+
+    // consume the next character if it is `ch`
+    fn eat(&mut self, ch: char) -> bool {
+        if self.string.clone().next() == Some(ch) {
+            self.string.next();
+            true
+        } else {
+            false
         }
     }
 
-    fn next_token_with_bracket(&mut self) -> Token {
+    // after "(?"
+    fn next_token_with_question(&mut self) -> Result<Token> {
         use Token::*;
-        let mut min = 0;
-        let mut max = None; // -1 indicates unbounded
-        let mut maybe_unbound = true;
+        let mut rest = self.string.clone();
+        let token = match (rest.next(), rest.next()) {
+            (Some('='), _) => TkLookAhead,
+            (Some('!'), _) => TkNegLookAhead,
+            (Some('<'), Some('=')) => TkLookBehind,
+            (Some('<'), Some('!')) => TkNegLookBehind,
+            _ => return self.next_token_with_flags(),
+        };
+        let len = if matches!(token, TkLookAhead | TkNegLookAhead) { 1 } else { 2 };
+        self.string.nth(len - 1);
+        self.scopes.push(self.verbose);
+        Ok(token)
+    }
+
+    fn next_token_with_flags(&mut self) -> Result<Token> {
+        use Token::*;
+        let mut delta = FlagDelta::default();
+        let mut negated = false;
+        // no flag yet since "(?" or '-': (?) and (?i-) set nothing, only (?: may
+        let mut missing = true;
+        while let Some(ch) = self.string.next() {
+            let flags = if negated { &mut delta.off } else { &mut delta.on };
+            match ch {
+                'i' => flags.case_insensitive = true,
+                's' => flags.dot_matches_new_line = true,
+                'x' => flags.verbose = true,
+                '-' if !negated => negated = true,
+                ')' | ':' if missing && (ch == ')' || negated) => {
+                    let end = self.pos();
+                    return Err(CompileError::MissingFlag { span: end - 1..end });
+                }
+                ')' => {
+                    self.verbose = delta.apply(self.flags()).verbose;
+                    return Ok(TkSetFlags(delta));
+                }
+                ':' => {
+                    self.scopes.push(self.verbose);
+                    self.verbose = delta.apply(self.flags()).verbose;
+                    return Ok(TkFlagGroup(delta));
+                }
+                _ => {
+                    let end = self.pos();
+                    let span = end - ch.len_utf8()..end;
+                    return Err(CompileError::UnknownFlag { flag: ch, span });
+                }
+            }
+            missing = ch == '-';
+            // only the flag just read can be on both sides
+            if delta.on.minus(delta.off) != delta.on {
+                let end = self.pos();
+                let span = end - ch.len_utf8()..end;
+                return Err(CompileError::ConflictingFlag { flag: ch, span });
+            }
+        }
+        Err(CompileError::UnbalancedParen { span: self.span() })
+    }
+
+    // the flags the lexer itself keeps track of
+    fn flags(&self) -> Flags {
+        Flags { verbose: self.verbose, ..Flags::default() }
+    }
+
+    fn next_token_with_bracket(&mut self) -> Result<Token> {
+        use RepetitionError::*;
+        use Token::*;
+        let mut min = None;
+        let mut max = None;
+        let mut comma = false;
         while let Some(ch) = self.string.next() {
             match ch {
                 '0'..='9' => {
                     let d = ch.to_digit(10).unwrap();
-                    if maybe_unbound {
-                        min = min * 10 + d;
-                    } else {
-                        max = Some(max.unwrap() * 10 + d);
-                    }
+                    let bound: &mut Option<u32> = if comma { &mut max } else { &mut min };
+                    let value = bound.unwrap_or(0).checked_mul(10).and_then(|n| n.checked_add(d));
+                    *bound = Some(value.ok_or_else(|| self.bad_repetition(Overflow))?);
                 }
-                ',' => {
-                    if maybe_unbound { 
-                        maybe_unbound = false; 
-                        max = Some(0);
-                    } else { 
-                        return TkEps;           // unexpected case
-                    } 
+                ',' if !comma => comma = true,
+                '}' => {
+                    return match (min, comma, max) {
+                        // {n}
+                        (Some(n), false, _) => Ok(TkRepeat(n, Some(n))),
+                        // {n,}
+                        (Some(min), true, None) => Ok(TkRepeat(min, None)),
+                        // {n,m} and {,m}
+                        (min, true, Some(max)) if min.unwrap_or(0) > max => {
+                            Err(self.bad_repetition(MinExceedsMax))
+                        }
+                        (min, true, Some(max)) => Ok(TkRepeat(min.unwrap_or(0), Some(max))),
+                        (None, _, _) => Err(self.bad_repetition(InvalidContent)),
+                    };
                 }
-                '}' => return TkBracket (min, max),
                 ' ' | '\n' | '\t' => continue, // skip whitespace
-                _ => return TkEps,             // unexpected character
+                _ => return Err(self.bad_repetition(InvalidContent)),
             }
         }
-        TkEps
+        Err(self.bad_repetition(Unterminated))
+    }
+
+    fn bad_repetition(&self, kind: RepetitionError) -> CompileError {
+        CompileError::BadRepetition { kind, span: self.span() }
     }
 }
 
@@ -99,59 +386,232 @@ mod tests {
     #[test]
     fn scan_chars() {
         let mut lexer = Lexer::new("a|b* (c|d)");
-        assert_eq!(lexer.next_token(), (Token::TkChar('a')));
-        assert_eq!(lexer.next_token(), (Token::TkOr));
-        assert_eq!(lexer.next_token(), (Token::TkChar('b')));
-        assert_eq!(lexer.next_token(), (Token::TkStar));
-        assert_eq!(lexer.next_token(), (Token::TkLparen));
-        assert_eq!(lexer.next_token(), (Token::TkChar('c')));
-        assert_eq!(lexer.next_token(), (Token::TkOr));
-        assert_eq!(lexer.next_token(), (Token::TkChar('d')));
-        assert_eq!(lexer.next_token(), (Token::TkRparen));
-        assert_eq!(lexer.next_token(), (Token::TkEps));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('a')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkOr));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('b')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkStar));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar(' ')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkLparen));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('c')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkOr));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('d')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRparen));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
     }
 
     #[test]
     fn scan_escape() {
-        let mut lexer = Lexer::new("\\a|\\b* (c|d)e?");
-        assert_eq!(lexer.next_token(), (Token::TkChar('a')));
-        assert_eq!(lexer.next_token(), (Token::TkOr));
-        assert_eq!(lexer.next_token(), (Token::TkChar('b')));
-        assert_eq!(lexer.next_token(), (Token::TkStar));
-        assert_eq!(lexer.next_token(), (Token::TkLparen));
-        assert_eq!(lexer.next_token(), (Token::TkChar('c')));
-        assert_eq!(lexer.next_token(), (Token::TkOr));
-        assert_eq!(lexer.next_token(), (Token::TkChar('d')));
-        assert_eq!(lexer.next_token(), (Token::TkRparen));
-        assert_eq!(lexer.next_token(), (Token::TkChar('e')));
-        assert_eq!(lexer.next_token(), (Token::TkQuestion));
-        assert_eq!(lexer.next_token(), (Token::TkEps));
+        let mut lexer = Lexer::new("\\*|\\n* (c|d)e?");
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('*')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkOr));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('\n')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkStar));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar(' ')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkLparen));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('c')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkOr));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('d')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRparen));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('e')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkQuestion));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+    }
+
+    #[test]
+    fn scan_escape_sequence() {
+        let mut lexer = Lexer::new("\\t\\r\\0\\x41\\u{3042}\\u{1F600}\\ ");
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('\t')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('\r')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('\0')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('A')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('あ')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('😀')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar(' ')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+        // an escaped dot is a character, not the wildcard
+        let mut lexer = Lexer::new("\\.\\x2E\\u{2E}.");
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('.')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('.')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('.')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkAny));
+        for bad in ["\\a", "\\", "\\x4", "\\xZZ", "\\u41", "\\u{}", "\\u{D800}", "\\u{1234567}", "\\u{41"] {
+            assert!(Lexer::new(bad).next_token().is_err(), "{}", bad);
+        }
     }
 
     #[test]
     fn scan_whitespace() {
-        let mut lexer = Lexer::new("a\t \n| b+ (c | \td)");
-        assert_eq!(lexer.next_token(), (Token::TkChar('a')));
-        assert_eq!(lexer.next_token(), (Token::TkOr));
-        assert_eq!(lexer.next_token(), (Token::TkChar('b')));
-        assert_eq!(lexer.next_token(), (Token::TkPlus));
-        assert_eq!(lexer.next_token(), (Token::TkLparen));
-        assert_eq!(lexer.next_token(), (Token::TkChar('c')));
-        assert_eq!(lexer.next_token(), (Token::TkOr));
-        assert_eq!(lexer.next_token(), (Token::TkChar('d')));
-        assert_eq!(lexer.next_token(), (Token::TkRparen));
-        assert_eq!(lexer.next_token(), (Token::TkEps));
+        let mut lexer = Lexer::new("a b\t|\n");
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('a')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar(' ')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('b')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('\t')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkOr));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('\n')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+    }
+
+    #[test]
+    fn scan_verbose() {
+        let verbose = Flags { verbose: true, ..Flags::default() };
+        let mut lexer = Lexer::new("a\t \n| b+ # comment\n(c | \td)\\ ").with_flags(verbose);
+        let verbose = FlagDelta { on: verbose, ..FlagDelta::default() };
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('a')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkOr));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('b')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkPlus));
+        assert_eq!(lexer.next_token(), Ok(Token::TkLparen));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('c')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkOr));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('d')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRparen));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar(' ')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+        // (?x) lasts until the end of the enclosing group
+        let mut lexer = Lexer::new("((?x) a) b");
+        assert_eq!(lexer.next_token(), Ok(Token::TkLparen));
+        assert_eq!(lexer.next_token(), Ok(Token::TkSetFlags(verbose)));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('a')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRparen));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar(' ')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('b')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
     }
 
     #[test]
     fn scan_bracket() {
-        let mut lexer = Lexer::new("a{2,3}b{0,}c{4}");
-        assert_eq!(lexer.next_token(), (Token::TkChar('a')));
-        assert_eq!(lexer.next_token(), (Token::TkBracket(2, Some(3))));
-        assert_eq!(lexer.next_token(), (Token::TkChar('b')));
-        assert_eq!(lexer.next_token(), (Token::TkBracket (0, Some(0))));
-        assert_eq!(lexer.next_token(), (Token::TkChar('c')));
-        assert_eq!(lexer.next_token(), (Token::TkBracket (4, None)));
-        assert_eq!(lexer.next_token(), (Token::TkEps));
+        let mut lexer = Lexer::new("a{2,3}b{0,}c{4}d{,5}e{0,0}");
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('a')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRepeat(2, Some(3))));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('b')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRepeat(0, None)));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('c')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRepeat(4, Some(4))));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('d')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRepeat(0, Some(5))));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('e')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRepeat(0, Some(0))));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+    }
+
+    #[test]
+    fn scan_bad_bracket() {
+        use RepetitionError::*;
+        for (pattern, kind) in [
+            ("{2,x}", InvalidContent),
+            ("{1,2,3}", InvalidContent),
+            ("{}", InvalidContent),
+            ("{,}", InvalidContent),
+            ("{3", Unterminated),
+            ("{", Unterminated),
+            ("{4,2}", MinExceedsMax),
+            ("{4294967296}", Overflow),
+            ("{1,99999999999}", Overflow),
+        ] {
+            match Lexer::new(pattern).next_token() {
+                Err(CompileError::BadRepetition { kind: actual, .. }) => assert_eq!(actual, kind, "{}", pattern),
+                other => panic!("{}: {:?}", pattern, other),
+            }
+        }
+        let mut lexer = Lexer::new("{4294967295}\\{");
+        assert_eq!(lexer.next_token(), Ok(Token::TkRepeat(u32::MAX, Some(u32::MAX))));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('{')));
+    }
+
+    #[test]
+    fn scan_lazy() {
+        let mut lexer = Lexer::new("a*?b+?c??d{1,2}?e?");
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('a')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkLazyStar));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('b')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkLazyPlus));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('c')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkLazyQuestion));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('d')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkLazyRepeat(1, Some(2))));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('e')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkQuestion));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+    }
+
+    #[test]
+    fn scan_flags() {
+        let ci = Flags { case_insensitive: true, ..Flags::default() };
+        let dot = Flags { dot_matches_new_line: true, ..Flags::default() };
+        let mut lexer = Lexer::new("(?i)a(?:b)(?is-x:c)(?-s)");
+        assert_eq!(lexer.next_token(), Ok(Token::TkSetFlags(FlagDelta { on: ci, ..FlagDelta::default() })));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('a')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkFlagGroup(FlagDelta::default())));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('b')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRparen));
+        assert_eq!(
+            lexer.next_token(),
+            Ok(Token::TkFlagGroup(FlagDelta {
+                on: ci.union(dot),
+                off: Flags { verbose: true, ..Flags::default() }
+            }))
+        );
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('c')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkRparen));
+        assert_eq!(lexer.next_token(), Ok(Token::TkSetFlags(FlagDelta { off: dot, ..FlagDelta::default() })));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+        for bad in ["(?q)", "(?i", "(?i--s)", "(?)", "(?-)", "(?i-)", "(?-:a)"] {
+            assert!(Lexer::new(bad).next_token().is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn scan_lookaround() {
+        let mut lexer = Lexer::new("(?=a)(?!b)(?<=c)(?<!d)");
+        for (look, ch) in [
+            (Token::TkLookAhead, 'a'),
+            (Token::TkNegLookAhead, 'b'),
+            (Token::TkLookBehind, 'c'),
+            (Token::TkNegLookBehind, 'd'),
+        ] {
+            assert_eq!(lexer.next_token(), Ok(look));
+            assert_eq!(lexer.next_token(), Ok(Token::TkChar(ch)));
+            assert_eq!(lexer.next_token(), Ok(Token::TkRparen));
+        }
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+    }
+
+    #[test]
+    fn scan_class() {
+        let mut lexer = Lexer::new(r"[^a.(\]-][ ]]");
+        assert_eq!(lexer.next_token(), Ok(Token::TkClassOpen(true)));
+        for ch in ['a', '.', '(', ']', '-'] {
+            assert_eq!(lexer.next_token(), Ok(Token::TkChar(ch)));
+        }
+        assert_eq!(lexer.next_token(), Ok(Token::TkClassClose));
+        assert_eq!(lexer.next_token(), Ok(Token::TkClassOpen(false)));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar(' ')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkClassClose));
+        // outside a class, ']' is a character
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar(']')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+        // '-' first, last or escaped; a-z would be a range
+        let mut lexer = Lexer::new(r"[-a][^-][a\-b][a-z]");
+        let tokens: Vec<_> = std::iter::from_fn(|| Some(lexer.next_token())).take(15).collect();
+        assert_eq!(tokens[1], Ok(Token::TkChar('-')));
+        assert_eq!(tokens[5], Ok(Token::TkChar('-')));
+        assert_eq!(tokens[9], Ok(Token::TkChar('-')));
+        assert_eq!(tokens[14], Err(CompileError::ClassRange { span: 16..17 }));
+    }
+
+    #[test]
+    fn scan_diff() {
+        let mut lexer = Lexer::new("a-b--c");
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('a')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('-')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('b')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkDiff));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('c')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
+        // escaped, two dashes are characters
+        let mut lexer = Lexer::new(r"-\-");
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('-')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkChar('-')));
+        assert_eq!(lexer.next_token(), Ok(Token::TkEps));
     }
 }
\ No newline at end of file
@@ -1,8 +1,29 @@
+mod builder;
+mod decide;
+mod dfa;
+mod display;
+mod dot;
+mod engine;
+mod error;
+mod fold;
+mod fold_table;
+mod generate;
 mod lex;
+mod nfa;
 mod parse;
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+mod simplify;
+pub use builder::KyomuRegexBuilder;
+pub use dfa::{CharClass, Dfa, LazyDfa};
+pub use engine::{Engine, Matcher};
+pub use error::{CompileError, DfaError, NfaError, RepetitionError, Span, TokenKind};
+pub use generate::{Generator, Rng, Strings};
+pub use nfa::Nfa;
+use lex::Flags;
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum KyomuRegex {
     Char(char),                               // a single character
+    Any,                                      // any character, including \n
+    AnyExcept(Vec<char>),                     // any character but these, sorted: [^ab], or . for [^\n]
     Eps,                                      // ε
     Empty,                                    // ∅
     Concat(Box<KyomuRegex>, Box<KyomuRegex>), // ⋅
@@ -10,7 +31,14 @@ pub enum KyomuRegex {
     Star(Box<KyomuRegex>),                    // *
     Plus(Box<KyomuRegex>),                    // +
     Question(Box<KyomuRegex>),                // ?
-    Bracket(u32, Option<u32>, Box<KyomuRegex>),       // {min, max}
+    Repeat(u32, Option<u32>, Box<KyomuRegex>),       // {min, max}; max == None means unbounded
+    And(Box<KyomuRegex>, Box<KyomuRegex>),    // &
+    Not(Box<KyomuRegex>),                     // ~
+    Lazy(Box<KyomuRegex>),                    // non-greedy quantifier
+    LookAhead(Box<KyomuRegex>),               // (?=r)
+    NegLookAhead(Box<KyomuRegex>),            // (?!r)
+    LookBehind(Box<KyomuRegex>, Box<KyomuRegex>),    // (?<=r), with D_w(.*r) for the input w read so far
+    NegLookBehind(Box<KyomuRegex>, Box<KyomuRegex>), // (?<!r), likewise
 }
 
 impl KyomuRegex {
@@ -21,41 +49,122 @@ impl KyomuRegex {
         }
         reg.match_eps()
     }
-    pub fn derivative(&self, ch: char) -> Self {
+    // The steps `whole_match` goes through: r, D_c1(r), D_c1c2(r), ... for
+    // input = c1c2..., each with whether it matches ε. The last one decides the match.
+    pub fn explain(&self, input: &str) -> Vec<(KyomuRegex, bool)> {
+        let mut steps = vec![self.clone()];
+        for ch in input.chars() {
+            let next = steps.last().unwrap().derivative(ch);
+            steps.push(next);
+        }
+        steps
+            .into_iter()
+            .map(|reg| {
+                let nullable = reg.match_eps();
+                (reg, nullable)
+            })
+            .collect()
+    }
+    // Returns the byte range of the leftmost match in `haystack`.
+    // At that position a lazy quantifier takes as few iterations as it can,
+    // deciding as it reads: `a+?b+` finds all of "abbb". Under & ~ and --,
+    // which match whole strings, it is greedy: `ab*?--x` finds "abbb" too.
+    // Otherwise the longest match is reported.
+    pub fn find(&self, haystack: &str) -> Option<std::ops::Range<usize>> {
+        let start = engine::leftmost_start(&mut engine::Derivatives, engine::start_scan(self), haystack)?;
+        // lookbehinds also see the input before `start`
+        let init = haystack[..start].chars().fold(self.clone(), |reg, ch| reg.tick(ch));
+        engine::preferred_end(&mut engine::Derivatives, init, haystack, start).map(|end| start..end)
+    }
+    // Returns the byte range of the rightmost match in `haystack`, i.e. what
+    // `find` reports when both the pattern and the haystack are reversed.
+    pub fn rfind(&self, haystack: &str) -> Option<std::ops::Range<usize>> {
+        let reversed: String = haystack.chars().rev().collect();
+        let found = self.reverse().find(&reversed)?;
+        Some(haystack.len() - found.end..haystack.len() - found.start)
+    }
+    // A regex matching the reversed strings, with lookaheads and lookbehinds swapped.
+    // Lookbehinds start over, forgetting the input they have seen.
+    pub fn reverse(&self) -> Self {
         use KyomuRegex::*;
-        // Helper to operate or
-        fn s_or(left: KyomuRegex, right: KyomuRegex) -> KyomuRegex {
-            match (left, right) {
-                (l, r) if l == r => l,
-                (Empty, r) => r,
-                (l, Empty) => l,
-                (l, r) => Or(Box::new(l), Box::new(r)),
+        let rev = |r: &KyomuRegex| Box::new(r.reverse());
+        // the lookbehind tracker .*r
+        let past = |r: &KyomuRegex| Box::new(Concat(Box::new(Star(Box::new(Any))), Box::new(r.clone())));
+        match self {
+            Char(_) | Any | AnyExcept(_) | Eps | Empty => self.clone(),
+            // (rs)^R = s^R r^R
+            Concat(left, right) => Concat(rev(right), rev(left)),
+            Or(left, right) => Or(rev(left), rev(right)),
+            And(left, right) => And(rev(left), rev(right)),
+            Star(r) => Star(rev(r)),
+            Plus(r) => Plus(rev(r)),
+            Question(r) => Question(rev(r)),
+            Repeat(min, max, r) => Repeat(*min, *max, rev(r)),
+            Not(r) => Not(rev(r)),
+            Lazy(r) => Lazy(rev(r)),
+            LookAhead(r) => {
+                let r = rev(r);
+                LookBehind(r.clone(), past(&r))
             }
+            NegLookAhead(r) => {
+                let r = rev(r);
+                NegLookBehind(r.clone(), past(&r))
+            }
+            LookBehind(r, _) => LookAhead(rev(r)),
+            NegLookBehind(r, _) => NegLookAhead(rev(r)),
         }
-        // Helper to operate concat
-        fn s_concat(left: KyomuRegex, right: KyomuRegex) -> KyomuRegex {
-            match (left, right) {
-                (Eps, r) => r,
-                (l, Eps) => l,
-                (Empty, _) | (_, Empty) => Empty,
-                (l, r) => Concat(Box::new(l), Box::new(r)),
+    }
+    pub(crate) fn is_lazy(&self) -> bool {
+        use KyomuRegex::*;
+        match self {
+            Lazy(_) => true,
+            Char(_) | Any | AnyExcept(_) | Eps | Empty => false,
+            LookAhead(_) | NegLookAhead(_) | LookBehind(_, _) | NegLookBehind(_, _) => false,
+            Concat(left, right) | Or(left, right) | And(left, right) => {
+                left.is_lazy() || right.is_lazy()
             }
+            Star(r) | Plus(r) | Question(r) | Repeat(_, _, r) | Not(r) => r.is_lazy(),
         }
+    }
+    // r -- s = r & ~s
+    pub fn difference(&self, other: &Self) -> Self {
+        KyomuRegex::And(
+            Box::new(self.clone()),
+            Box::new(KyomuRegex::Not(Box::new(other.clone()))),
+        )
+    }
+    // (r & ~s) | (s & ~r)
+    pub fn symmetric_difference(&self, other: &Self) -> Self {
+        KyomuRegex::Or(
+            Box::new(self.difference(other)),
+            Box::new(other.difference(self)),
+        )
+    }
+    pub fn derivative(&self, ch: char) -> Self {
+        use KyomuRegex::*;
         match self {
             Char(c) => {
-                if *c == '.' || *c == ch {
+                if *c == ch {
                     Eps
                 } else {
                     Empty
                 }
             } // D(c) = ε
+            Any => Eps,
+            AnyExcept(chars) => {
+                if chars.contains(&ch) {
+                    Empty
+                } else {
+                    Eps
+                }
+            }
             Eps => Empty,   // D(ε) = ∅
             Empty => Empty, // D(∅) = ∅
             Concat(left, right) => {
                 // D(left ⋅ right) = D(left) ⋅ right | δ(left) ⋅ D(right)
                 s_or(
-                    s_concat(left.derivative(ch), *right.clone()),
-                    s_concat(left.delta(), right.derivative(ch)),
+                    s_concat(left.derivative(ch), right.tick(ch)),
+                    s_concat(left.delta().advance(ch), right.derivative(ch)),
                 )
             }
             Or(left, right) => {
@@ -64,60 +173,132 @@ impl KyomuRegex {
             }
             Star(left) => {
                 // D(left*) = D(left) ⋅ left*
-                s_concat(left.derivative(ch), Star(left.clone()))
+                s_concat(left.derivative(ch), Star(Box::new(left.tick(ch))))
             }
             Plus(left) => {
                 // D(left+) = D(left) ⋅ left* | δ(left) ⋅ D(left) ⋅ left*
                 s_concat(
                     s_or(
                         left.derivative(ch),
-                        s_concat(left.delta(), left.derivative(ch)),
+                        s_concat(left.delta().advance(ch), left.derivative(ch)),
                     ),
-                    Star(left.clone()),
+                    Star(Box::new(left.tick(ch))),
                 )
             }
             Question(left) => {
                 // D(left?) = D(left)
                 left.derivative(ch)
             }
-            Bracket(min, max, r) => {
-                // D(r{min, max}) = D(r) ⋅ r{min-1, max-1} | δ(r) ⋅ r{min-1, max-1}
-                match (min, max) {
-                    // D(ε) = ∅ 
-                    (0, Some(0)) => Empty,
-                    // D(r{min, infty}) = D(r..r ⋅ r*)
-                    (_, Some(0)) => {
-                        let rr = (0..*min).fold(Eps, |acc, _| s_concat(*r.clone(), acc));
-                        s_concat(rr, Star(r.clone())).derivative(ch)
-                    }
-                    // D(r{min}) = D(r..r)
-                    (_, None) => {
-                        let rr = (0..*min).fold(Eps, |acc, _| s_concat(*r.clone(), acc));
-                        rr.derivative(ch)
-                    }
-
+            Repeat(min, max, r) => {
+                // The bounds act as counters, so the derivative stays the same size
+                // however large they are. When r is ambiguous several counts are
+                // live at once; `s_or` keeps them as one interval.
+                let cond = r.delta();
+                // when r matches ε anyway, any iteration can be skipped
+                let lower = if cond == Eps { 0 } else { *min };
+                match max {
                     // invalid case (e.g., {4,2})
-                    (_, _) if *min > max.unwrap() => Empty,
-
-                    // D(r{min, max}) = D(r) ⋅ r{min-1, max-1} | δ(r) ⋅ r{min-1, max-1}
-                    (_, _) => s_or(
-                        s_concat(
-                            r.derivative(ch),
-                            Bracket(min.saturating_sub(1), Some(max.unwrap().saturating_sub(1)), r.clone()),
-                        ),
-                        s_concat(
-                            r.delta(),
-                            Bracket(min.saturating_sub(1), Some(max.unwrap().saturating_sub(1)), r.clone()),
-                        ),
-                    ),
+                    Some(max) if min > max => Empty,
+                    // D(r{0,0}) = D(ε) = ∅
+                    Some(0) => Empty,
+                    // D(r{min, max}) = D(r) ⋅ r{min-1, max-1} | δ(r) ⋅ D(r{min-1, max-1})
+                    _ => {
+                        let rest = Repeat(lower.saturating_sub(1), max.map(|max| max - 1), r.clone());
+                        // with min == 0 the second term is already covered by the first
+                        let skip = if lower > 0 && cond != Empty {
+                            s_concat(cond.advance(ch), rest.derivative(ch))
+                        } else {
+                            Empty
+                        };
+                        s_or(s_concat(r.derivative(ch), rest.tick(ch)), skip)
+                    }
                 }
             }
+            And(left, right) => {
+                // D(left & right) = D(left) & D(right)
+                s_and(left.derivative(ch), right.derivative(ch))
+            }
+            Not(left) => {
+                // D(~left) = ~D(left)
+                Not(Box::new(left.derivative(ch)))
+            }
+            Lazy(left) => {
+                // laziness only affects which match `find` reports
+                left.derivative(ch)
+            }
+            // lookarounds are zero-width; their condition is carried by δ
+            LookAhead(_) | NegLookAhead(_) | LookBehind(_, _) | NegLookBehind(_, _) => Empty,
+        }
+    }
+    // D(self), split by the lazy quantifiers that start another iteration on
+    // `ch`: each term comes with how many do. Leaving one costs nothing, and
+    // neither do the iterations its lower bound demands. The terms together
+    // make up D(self).
+    pub(crate) fn costed_derivative(&self, ch: char) -> Vec<(KyomuRegex, u32)> {
+        use KyomuRegex::*;
+        if !self.is_lazy() {
+            return vec![(self.derivative(ch), 0)];
+        }
+        // D(r) ⋅ rest, with `extra` added to each charge
+        let then = |r: &KyomuRegex, rest: KyomuRegex, extra: u32| -> Vec<(KyomuRegex, u32)> {
+            r.costed_derivative(ch)
+                .into_iter()
+                .map(|(d, cost)| (s_concat(d, rest.clone()), cost + extra))
+                .collect()
+        };
+        let b = |r: KyomuRegex| Box::new(r);
+        match self {
+            Concat(left, right) => {
+                let mut terms = then(left, right.tick(ch), 0);
+                let cond = left.delta();
+                if cond != Empty {
+                    let cond = cond.advance(ch);
+                    terms.extend(right.costed_derivative(ch).into_iter().map(|(d, cost)| (s_concat(cond.clone(), d), cost)));
+                }
+                terms
+            }
+            Or(left, right) => {
+                let mut terms = left.costed_derivative(ch);
+                terms.extend(right.costed_derivative(ch));
+                terms
+            }
+            Star(r) => then(r, Star(b(r.tick(ch))), 0),
+            Plus(r) => Concat(r.clone(), b(Star(r.clone()))).costed_derivative(ch),
+            Question(r) => r.costed_derivative(ch),
+            Repeat(min, max, r) => match max {
+                Some(max) if min > max => vec![],
+                Some(0) => vec![],
+                _ if *min > 0 => {
+                    let rest = Repeat(min - 1, max.map(|max| max - 1), r.clone());
+                    Concat(r.clone(), b(rest)).costed_derivative(ch)
+                }
+                _ => then(r, Repeat(0, max.map(|max| max - 1), b(r.tick(ch))), 0),
+            },
+            Lazy(q) => match &**q {
+                Star(r) => then(r, Lazy(b(Star(b(r.tick(ch))))), 1),
+                // the first iteration is not up to the quantifier
+                Plus(r) => Concat(r.clone(), b(Lazy(b(Star(r.clone()))))).costed_derivative(ch),
+                Question(r) => then(r, Eps, 1),
+                Repeat(min, max, r) => match max {
+                    Some(max) if min > max => vec![],
+                    Some(0) => vec![],
+                    _ if *min > 0 => {
+                        let rest = Lazy(b(Repeat(min - 1, max.map(|max| max - 1), r.clone())));
+                        Concat(r.clone(), b(rest)).costed_derivative(ch)
+                    }
+                    _ => then(r, Lazy(b(Repeat(0, max.map(|max| max - 1), b(r.tick(ch))))), 1),
+                },
+                q => q.costed_derivative(ch),
+            },
+            // & and ~ look at whole strings, so their lazy quantifiers are
+            // greedy, even once a side dies and leaves the other bare
+            _ => vec![(self.greedy().derivative(ch), 0)],
         }
     }
     pub fn match_eps(&self) -> bool {
         use KyomuRegex::*;
         match self {
-            Char(_) => false,
+            Char(_) | Any | AnyExcept(_) => false,
             Eps => true,
             Empty => false,
             Concat(left, right) => left.match_eps() && right.match_eps(),
@@ -125,49 +306,375 @@ impl KyomuRegex {
             Star(_) => true,
             Plus(r) => r.match_eps(),
             Question(_) => true,
-            Bracket(min, _, r) => *min == 0 || r.match_eps(),
+            Repeat(min, max, r) => {
+                max.is_none_or(|max| *min <= max) && (*min == 0 || r.match_eps())
+            }
+            And(left, right) => left.match_eps() && right.match_eps(),
+            Not(r) => !r.match_eps(),
+            Lazy(r) => r.match_eps(),
+            // at the end of the input
+            LookAhead(r) => r.match_eps(),
+            NegLookAhead(r) => !r.match_eps(),
+            LookBehind(_, past) => past.match_eps(),
+            NegLookBehind(_, past) => !past.match_eps(),
         }
     }
     // implementation of δ
+    // Without lookaheads this is ε or ∅. Otherwise the result is built from
+    // ε, ∅, ⋅, | and lookaheads, and tells what the rest of the input must
+    // satisfy for `self` to match the empty string here.
     pub fn delta(&self) -> KyomuRegex {
         use KyomuRegex::*;
-        if self.match_eps() {
-            Eps
-        } else {
-            Empty
+        match self {
+            Char(_) | Any | AnyExcept(_) | Empty => Empty,
+            Eps | Star(_) | Question(_) => Eps,
+            Concat(left, right) | And(left, right) => s_concat(left.delta(), right.delta()),
+            Or(left, right) => s_or(left.delta(), right.delta()),
+            Plus(r) | Lazy(r) => r.delta(),
+            Repeat(min, Some(max), _) if min > max => Empty,
+            Repeat(0, _, _) => Eps,
+            Repeat(_, _, r) => r.delta(),
+            Not(r) => r.delta().negate(),
+            LookAhead(r) if r.match_eps() => Eps,
+            NegLookAhead(r) if r.match_eps() => Empty,
+            // a lookahead nothing can satisfy any more
+            LookAhead(r) if **r == Empty => Empty,
+            NegLookAhead(r) if **r == Empty => Eps,
+            LookAhead(_) | NegLookAhead(_) => self.clone(),
+            LookBehind(_, past) if past.match_eps() => Eps,
+            NegLookBehind(_, past) if !past.match_eps() => Eps,
+            LookBehind(_, _) | NegLookBehind(_, _) => Empty,
+        }
+    }
+    // whether `self` is a condition returned by `delta`, other than ε and ∅
+    fn is_condition(&self) -> bool {
+        use KyomuRegex::*;
+        match self {
+            LookAhead(_) | NegLookAhead(_) => true,
+            Concat(left, right) | Or(left, right) => left.is_condition() && right.is_condition(),
+            _ => false,
+        }
+    }
+    // complement of a condition returned by `delta`
+    fn negate(&self) -> KyomuRegex {
+        use KyomuRegex::*;
+        match self {
+            Eps => Empty,
+            Empty => Eps,
+            Concat(left, right) => s_or(left.negate(), right.negate()),
+            Or(left, right) => s_concat(left.negate(), right.negate()),
+            LookAhead(r) => NegLookAhead(r.clone()),
+            NegLookAhead(r) => LookAhead(r.clone()),
+            _ => unreachable!("not a condition: {:?}", self),
+        }
+    }
+    // the condition returned by `delta` after reading `ch`
+    fn advance(&self, ch: char) -> KyomuRegex {
+        use KyomuRegex::*;
+        match self {
+            Eps | Empty => self.clone(),
+            Concat(left, right) => s_concat(left.advance(ch), right.advance(ch)),
+            Or(left, right) => s_or(left.advance(ch), right.advance(ch)),
+            LookAhead(r) => LookAhead(Box::new(r.derivative(ch))).delta(),
+            NegLookAhead(r) => NegLookAhead(Box::new(r.derivative(ch))).delta(),
+            _ => unreachable!("not a condition: {:?}", self),
+        }
+    }
+    // whether a condition returned by `delta` holds when `rest` follows
+    pub(crate) fn holds_on(&self, rest: &str) -> bool {
+        let mut cond = self.clone();
+        for ch in rest.chars() {
+            if cond == KyomuRegex::Eps || cond == KyomuRegex::Empty {
+                break;
+            }
+            cond = cond.advance(ch);
+        }
+        cond.match_eps()
+    }
+    // `self` with `ch` appended to the input seen by its lookbehinds
+    fn tick(&self, ch: char) -> KyomuRegex {
+        use KyomuRegex::*;
+        let tick = |r: &KyomuRegex| Box::new(r.tick(ch));
+        match self {
+            Char(_) | Any | AnyExcept(_) | Eps | Empty | LookAhead(_) | NegLookAhead(_) => self.clone(),
+            Concat(left, right) => Concat(tick(left), tick(right)),
+            Or(left, right) => Or(tick(left), tick(right)),
+            And(left, right) => And(tick(left), tick(right)),
+            Star(r) => Star(tick(r)),
+            Plus(r) => Plus(tick(r)),
+            Question(r) => Question(tick(r)),
+            Repeat(min, max, r) => Repeat(*min, *max, tick(r)),
+            Not(r) => Not(tick(r)),
+            Lazy(r) => Lazy(tick(r)),
+            LookBehind(r, past) => LookBehind(r.clone(), Box::new(past.derivative(ch))),
+            NegLookBehind(r, past) => NegLookBehind(r.clone(), Box::new(past.derivative(ch))),
+        }
+    }
+    // `self` with its lazy quantifiers made greedy
+    fn greedy(&self) -> KyomuRegex {
+        use KyomuRegex::*;
+        if !self.is_lazy() {
+            return self.clone();
+        }
+        let greedy = |r: &KyomuRegex| Box::new(r.greedy());
+        match self {
+            Concat(left, right) => Concat(greedy(left), greedy(right)),
+            Or(left, right) => Or(greedy(left), greedy(right)),
+            And(left, right) => And(greedy(left), greedy(right)),
+            Star(r) => Star(greedy(r)),
+            Plus(r) => Plus(greedy(r)),
+            Question(r) => Question(greedy(r)),
+            Repeat(min, max, r) => Repeat(*min, *max, greedy(r)),
+            Not(r) => Not(greedy(r)),
+            Lazy(r) => r.greedy(),
+            _ => self.clone(),
         }
     }
 
-    fn build_from_ast(node: crate::parse::Node) -> Self {
+    fn build_from_ast(node: crate::parse::Node, flags: Flags) -> Self {
         use crate::parse::Node::*;
         use KyomuRegex::*;
+        let build = |node: Box<crate::parse::Node>| Box::new(Self::build_from_ast(*node, flags));
         match node {
+            // (?i) matches every character folding to the same one
+            NdChar(c) if flags.case_insensitive => fold::case_orbit(c)
+                .into_iter()
+                .map(Char)
+                .reduce(|l, r| Or(Box::new(l), Box::new(r)))
+                .unwrap(),
+            // Without (?s), . matches anything but a newline, as in most engines.
+            // This is the default.
+            NdAny if !flags.dot_matches_new_line => AnyExcept(vec!['\n']),
+            NdAny => Any,
             NdChar(c) => Char(c),
+            // [abc] is a|b|c, each folded under (?i)
+            NdClass(false, chars) => chars
+                .into_iter()
+                .map(|c| Self::build_from_ast(NdChar(c), flags))
+                .reduce(|l, r| Or(Box::new(l), Box::new(r)))
+                .unwrap_or(Empty),
+            NdClass(true, chars) => {
+                let mut chars: Vec<char> = match flags.case_insensitive {
+                    true => chars.into_iter().flat_map(fold::case_orbit).collect(),
+                    false => chars,
+                };
+                chars.sort();
+                chars.dedup();
+                AnyExcept(chars)
+            }
             NdEps => Eps,
-            NdStar(left) => Star(Box::new(Self::build_from_ast(*left))),
-            NdPlus(left) => Plus(Box::new(Self::build_from_ast(*left))),
-            NdQuestion(left) => Question(Box::new(Self::build_from_ast(*left))),
-            NdConcat(left, right) => Concat(
-                Box::new(Self::build_from_ast(*left)),
-                Box::new(Self::build_from_ast(*right)),
-            ),
-            NdOr(left, right) => Or(
-                Box::new(Self::build_from_ast(*left)),
-                Box::new(Self::build_from_ast(*right)),
-            ),
-            NdBracket(min, max, r) => Bracket(min, max, Box::new(Self::build_from_ast(*r))),
-        }
-    }
-
-    pub fn compile(pattern: &str) -> Result<Self, String> {
-        let mut parser = crate::parse::Parser::new(crate::lex::Lexer::new(pattern));
+            NdStar(left) => Star(build(left)),
+            NdPlus(left) => Plus(build(left)),
+            NdQuestion(left) => Question(build(left)),
+            NdConcat(left, right) => Concat(build(left), build(right)),
+            NdOr(left, right) => Or(build(left), build(right)),
+            NdRepeat(min, max, r) => Repeat(min, max, build(r)),
+            NdLazy(r) => Lazy(build(r)),
+            NdDiff(left, right) => build(left).difference(&build(right)),
+            NdGroup(delta, r) => Self::build_from_ast(*r, delta.apply(flags)),
+            NdLookAhead(r) => LookAhead(build(r)),
+            NdNegLookAhead(r) => NegLookAhead(build(r)),
+            NdLookBehind(r) => {
+                let r = build(r);
+                let past = Concat(Box::new(Star(Box::new(Any))), r.clone());
+                LookBehind(r, Box::new(past))
+            }
+            NdNegLookBehind(r) => {
+                let r = build(r);
+                let past = Concat(Box::new(Star(Box::new(Any))), r.clone());
+                NegLookBehind(r, Box::new(past))
+            }
+        }
+    }
+
+    // Note that . does not match a newline unless (?s) is set (or
+    // `KyomuRegexBuilder::dot_matches_new_line`); before flags existed it did.
+    pub fn compile(pattern: &str) -> Result<Self, CompileError> {
+        Self::compile_with_flags(pattern, Flags::default())
+    }
+
+    pub(crate) fn compile_with_flags(pattern: &str, flags: Flags) -> Result<Self, CompileError> {
+        let mut parser = crate::parse::Parser::new(crate::lex::Lexer::new(pattern).with_flags(flags))?;
         let ast = parser.parse()?;
-        Ok(Self::build_from_ast(ast))
+        Ok(Self::build_from_ast(ast, flags).simplify())
+    }
+}
+
+// Helper to operate or
+// The alternatives are kept flat, sorted and free of duplicates, so derivatives
+// that differ only by the order or grouping of | compare equal. This keeps the
+// set of derivatives of a regex finite.
+fn s_or(left: KyomuRegex, right: KyomuRegex) -> KyomuRegex {
+    use KyomuRegex::*;
+    let mut terms = Vec::new();
+    alternatives(left, &mut terms);
+    alternatives(right, &mut terms);
+    let mut terms = merge_counters(terms);
+    terms.sort();
+    terms.dedup();
+    let last = terms.pop().unwrap_or(Empty);
+    terms.into_iter().rev().fold(last, |acc, r| Or(Box::new(r), Box::new(acc)))
+}
+// the alternatives of a chain of |, as they come
+pub(crate) fn alternatives(r: KyomuRegex, out: &mut Vec<KyomuRegex>) {
+    match r {
+        KyomuRegex::Or(left, right) => {
+            alternatives(*left, out);
+            alternatives(*right, out);
+        }
+        // ∅ | r = r
+        KyomuRegex::Empty => {}
+        r => out.push(r),
+    }
+}
+// p1 ⋅ (p2 ⋅ (... ⋅ r{lo,hi})) as ([p1, p2, ...], lo, hi, r)
+fn counted(r: &KyomuRegex) -> Option<(Vec<KyomuRegex>, u32, Option<u32>, &KyomuRegex)> {
+    use KyomuRegex::*;
+    match r {
+        Repeat(lo, hi, body) => Some((vec![], *lo, *hi, body)),
+        Concat(left, right) => {
+            let (mut prefix, lo, hi, body) = counted(right)?;
+            prefix.insert(0, (**left).clone());
+            Some((prefix, lo, hi, body))
+        }
+        _ => None,
+    }
+}
+// p ⋅ r{a,b} | p ⋅ r{c,d} = p ⋅ r{min(a,c),max(b,d)} when the counts overlap or touch.
+// When r can match in several ways, as in (a|aa){0,200}, each way leaves its
+// own count behind, and without this a derivative would keep one term per
+// count the input allows: the counts form an interval, kept as one term.
+fn merge_counters(terms: Vec<KyomuRegex>) -> Vec<KyomuRegex> {
+    use KyomuRegex::*;
+    type Group = (Vec<KyomuRegex>, KyomuRegex, Vec<(u32, Option<u32>)>);
+    let mut groups: Vec<Group> = Vec::new();
+    let mut others = Vec::new();
+    for term in terms {
+        let Some((prefix, lo, hi, body)) = counted(&term) else {
+            others.push(term);
+            continue;
+        };
+        // r{4,2} = ∅
+        if hi.is_some_and(|hi| lo > hi) {
+            continue;
+        }
+        match groups.iter_mut().find(|(p, b, _)| *p == prefix && b == body) {
+            Some((_, _, counts)) => counts.push((lo, hi)),
+            None => groups.push((prefix, body.clone(), vec![(lo, hi)])),
+        }
+    }
+    for (prefix, body, mut counts) in groups {
+        counts.sort();
+        let mut merged: Vec<(u32, Option<u32>)> = Vec::new();
+        for (lo, hi) in counts {
+            match merged.last_mut() {
+                // None is unbounded, so it absorbs every later count
+                Some((_, last)) if last.is_none_or(|last| lo <= last.saturating_add(1)) => {
+                    *last = last.zip(hi).map(|(a, b)| a.max(b));
+                }
+                _ => merged.push((lo, hi)),
+            }
+        }
+        for (lo, hi) in merged {
+            let repeat = Repeat(lo, hi, Box::new(body.clone()));
+            others.push(prefix.iter().rev().fold(repeat, |acc, p| s_concat(p.clone(), acc)));
+        }
+    }
+    others
+}
+// Helper to operate concat
+// Conditions from `delta` in a row all hold at the same place, so they are
+// kept sorted and free of duplicates like the terms of `s_or`. Otherwise a
+// lookahead under a star would add one more copy for each character read.
+fn s_concat(left: KyomuRegex, right: KyomuRegex) -> KyomuRegex {
+    use KyomuRegex::*;
+    match (left, right) {
+        (Eps, r) => r,
+        (l, Eps) => l,
+        (Empty, _) | (_, Empty) => Empty,
+        (l, r) if l.is_condition() => {
+            let mut conditions = Vec::new();
+            factors(l, &mut conditions);
+            // the conditions at the front of `r`
+            let mut rest = r;
+            loop {
+                match rest {
+                    Concat(c, r) if c.is_condition() => {
+                        factors(*c, &mut conditions);
+                        rest = *r;
+                    }
+                    c if c.is_condition() => {
+                        factors(c, &mut conditions);
+                        rest = Eps;
+                    }
+                    r => {
+                        rest = r;
+                        break;
+                    }
+                }
+            }
+            conditions.sort();
+            conditions.dedup();
+            // (?=r)(?!r) = ∅
+            let contradiction = conditions
+                .iter()
+                .any(|c| matches!(c, NegLookAhead(r) if conditions.contains(&LookAhead(r.clone()))));
+            if contradiction {
+                return Empty;
+            }
+            conditions
+                .into_iter()
+                .rev()
+                .fold(rest, |acc, c| match acc {
+                    Eps => c,
+                    acc => Concat(Box::new(c), Box::new(acc)),
+                })
+        }
+        (l, r) => Concat(Box::new(l), Box::new(r)),
+    }
+}
+// the factors of a chain of ⋅
+pub(crate) fn factors(r: KyomuRegex, out: &mut Vec<KyomuRegex>) {
+    match r {
+        KyomuRegex::Concat(left, right) => {
+            factors(*left, out);
+            factors(*right, out);
+        }
+        r => out.push(r),
+    }
+}
+// Helper to operate and, normalized like `s_or`
+fn s_and(left: KyomuRegex, right: KyomuRegex) -> KyomuRegex {
+    use KyomuRegex::*;
+    let mut terms = Vec::new();
+    conjuncts(left, &mut terms);
+    conjuncts(right, &mut terms);
+    // r & ~r = ∅
+    let contradiction = terms.iter().any(|r| matches!(r, Not(r) if terms.contains(r)));
+    if contradiction || terms.contains(&Empty) {
+        return Empty;
+    }
+    terms.sort();
+    terms.dedup();
+    // an empty conjunction matches everything
+    let last = terms.pop().unwrap_or_else(|| Not(Box::new(Empty)));
+    terms.into_iter().rev().fold(last, |acc, r| And(Box::new(r), Box::new(acc)))
+}
+// the conjuncts of a chain of &, as they come
+pub(crate) fn conjuncts(r: KyomuRegex, out: &mut Vec<KyomuRegex>) {
+    match r {
+        KyomuRegex::And(left, right) => {
+            conjuncts(*left, out);
+            conjuncts(*right, out);
+        }
+        // r & ~∅ = r
+        KyomuRegex::Not(r) if *r == KyomuRegex::Empty => {}
+        r => out.push(r),
     }
 }
 
 impl std::str::FromStr for KyomuRegex {
-    type Err = String;
+    type Err = CompileError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         Self::compile(s)
     }
@@ -177,6 +684,20 @@ impl std::str::FromStr for KyomuRegex {
 mod tests {
     use super::*;
 
+    // every string of at most `len` characters of `alphabet`, shortest first
+    pub(crate) fn strings_up_to(alphabet: &[char], len: usize) -> Vec<String> {
+        let mut all = vec![String::new()];
+        let mut last = vec![String::new()];
+        for _ in 0..len {
+            last = last
+                .iter()
+                .flat_map(|s| alphabet.iter().map(move |c| format!("{}{}", s, c)))
+                .collect();
+            all.extend(last.iter().cloned());
+        }
+        all
+    }
+
     macro_rules! chr {
         ($ch:expr) => {
             KyomuRegex::Char($ch)
@@ -212,6 +733,19 @@ mod tests {
         assert!(!r.whole_match("abc"));
     }
 
+    #[test]
+    fn explain_steps() {
+        let r = KyomuRegex::compile("ab*").unwrap();
+        let steps = r.explain("abb");
+        assert_eq!(steps.len(), 4);
+        assert_eq!(steps[0], (r.clone(), false));
+        assert_eq!(steps[1], (r.derivative('a'), true));
+        assert_eq!(steps[3].0, r.derivative('a').derivative('b').derivative('b'));
+        let nullable: Vec<bool> = r.explain("ba").into_iter().map(|(_, n)| n).collect();
+        assert_eq!(nullable, vec![false, false, false]);
+        assert_eq!(r.explain("").last().unwrap().1, r.whole_match(""));
+    }
+
     #[test]
     fn parse_and_match_from_string() {
         let r: KyomuRegex = "a|(bc)*".parse().unwrap();
@@ -274,5 +808,360 @@ mod tests {
         assert!(r.whole_match("aa"));
         assert!(!r.whole_match("a"));
         assert!(!r.whole_match("aaa"));
+        let r: KyomuRegex = "a{0,0}b".parse().unwrap(); // == "b"
+        assert!(r.whole_match("b"));
+        assert!(!r.whole_match("aaaab"));
+        let r: KyomuRegex = "a{,2}b".parse().unwrap();
+        assert!(r.whole_match("b"));
+        assert!(r.whole_match("aab"));
+        assert!(!r.whole_match("aaab"));
+        let r: KyomuRegex = "(a?){2,3}".parse().unwrap();
+        assert!(r.whole_match("a"));
+        assert!(!r.whole_match("b"));
+    }
+
+    #[test]
+    fn parse_lazy() {
+        let r: KyomuRegex = "a*?b+?c??d{1,2}?".parse().unwrap();
+        assert!(r.whole_match("bd"));
+        assert!(r.whole_match("aabbcdd"));
+        assert!(!r.whole_match("ccd"));
+    }
+
+    #[test]
+    fn find_greedy_and_lazy() {
+        let greedy: KyomuRegex = "<.+>".parse().unwrap();
+        let lazy: KyomuRegex = "<.+?>".parse().unwrap();
+        let html = "x<a><b>y";
+        assert_eq!(greedy.find(html), Some(1..7));
+        assert_eq!(lazy.find(html), Some(1..4));
+        let r: KyomuRegex = "a*?".parse().unwrap();
+        assert_eq!(r.find("aaa"), Some(0..0));
+        let r: KyomuRegex = "b+".parse().unwrap();
+        assert_eq!(r.find("aabbb"), Some(2..5));
+        assert_eq!(r.find("aa"), None);
+        // each quantifier keeps its own preference
+        let r: KyomuRegex = "a+?b+".parse().unwrap();
+        assert_eq!(r.find("abbb"), Some(0..4));
+        let r: KyomuRegex = "<.+?>x*".parse().unwrap();
+        assert_eq!(r.find("<a>xx"), Some(0..5));
+        let r: KyomuRegex = "a??".parse().unwrap();
+        assert_eq!(r.find("a"), Some(0..0));
+        let r: KyomuRegex = "x{2,3}y*?".parse().unwrap();
+        assert_eq!(r.find("xxxyy"), Some(0..3));
+        // the lower bound is not up to the quantifier
+        let r: KyomuRegex = "(a?){2}?".parse().unwrap();
+        assert_eq!(r.find("aa"), Some(0..2));
+    }
+
+    #[test]
+    fn find_agrees_with_brute_force() {
+        // every way `r` matches s[i..]: its end, and how many lazy iterations
+        // it starts at each position of `s`
+        fn ways(r: &KyomuRegex, s: &[char], i: usize) -> Vec<(usize, Vec<u32>)> {
+            use KyomuRegex::*;
+            let none = || vec![0; s.len()];
+            match r {
+                Char(c) if s.get(i) == Some(c) => vec![(i + 1, none())],
+                Any if i < s.len() => vec![(i + 1, none())],
+                Eps => vec![(i, none())],
+                Concat(left, right) => ways(left, s, i)
+                    .into_iter()
+                    .flat_map(|(j, k)| {
+                        ways(right, s, j).into_iter().map(move |(end, l)| {
+                            (end, k.iter().zip(l).map(|(x, y)| x + y).collect())
+                        })
+                    })
+                    .collect(),
+                Or(left, right) => [ways(left, s, i), ways(right, s, i)].concat(),
+                Star(r) => iterate(r, s, i, false, 0, None),
+                Plus(r) => iterate(r, s, i, false, 1, None),
+                Question(r) => iterate(r, s, i, false, 0, Some(1)),
+                Repeat(min, max, r) => iterate(r, s, i, false, *min, *max),
+                Lazy(q) => match &**q {
+                    Star(r) => iterate(r, s, i, true, 0, None),
+                    Plus(r) => iterate(r, s, i, true, 1, None),
+                    Question(r) => iterate(r, s, i, true, 0, Some(1)),
+                    Repeat(min, max, r) => iterate(r, s, i, true, *min, *max),
+                    q => ways(q, s, i),
+                },
+                And(_, _) | Not(_) => (i..=s.len())
+                    .filter(|&j| r.whole_match(&s[i..j].iter().collect::<String>()))
+                    .map(|j| (j, none()))
+                    .collect(),
+                _ => vec![],
+            }
+        }
+        // `min` more iterations of r, then up to `max` - `min` that start on some input
+        fn iterate(r: &KyomuRegex, s: &[char], i: usize, lazy: bool, min: u32, max: Option<u32>) -> Vec<(usize, Vec<u32>)> {
+            if max.is_some_and(|max| min > max) {
+                return vec![];
+            }
+            let mut all = if min == 0 { vec![(i, vec![0; s.len()])] } else { vec![] };
+            if max == Some(0) {
+                return all;
+            }
+            for (j, mut k) in ways(r, s, i) {
+                if min == 0 && j == i {
+                    continue;
+                }
+                if lazy && min == 0 {
+                    k[i] += 1;
+                }
+                for (end, l) in iterate(r, s, j, lazy, min.saturating_sub(1), max.map(|max| max - 1)) {
+                    all.push((end, k.iter().zip(l).map(|(x, y)| x + y).collect()));
+                }
+            }
+            all
+        }
+        // the leftmost start, then the least charged way, then the longest
+        fn brute_force(r: &KyomuRegex, haystack: &str) -> Option<std::ops::Range<usize>> {
+            let chars: Vec<char> = haystack.chars().collect();
+            let bytes: Vec<usize> = haystack.char_indices().map(|(i, _)| i).chain([haystack.len()]).collect();
+            (0..=chars.len()).find_map(|start| {
+                let best = ways(r, &chars, start).into_iter().min_by(|(e, k), (f, l)| k.cmp(l).then(f.cmp(e)));
+                best.map(|(end, _)| bytes[start]..bytes[end])
+            })
+        }
+        let patterns = [
+            "b+", "a(b|c)*?", "(ab|b)*a", "c--c", "()", "(a|b)*--.*bb.*", "ba{2,}", "é+",
+            "a+?b+", "(a|ab)+?b*", "a*?(ab)?b*", "b{1,3}?a*", "(b+?a|b)*", "(a*?b)+c?", "a??b??a",
+            "ab*?--x", "ab*?--abbbbz", "(b+?--a)a*", "~(a*?b)", "b*?(a--b)",
+        ];
+        for pattern in patterns {
+            let r = KyomuRegex::compile(pattern).unwrap();
+            for haystack in ["", "a", "cab", "abcab", "bbaba", "xbaaay", "aébé", "abbb", "bbbaab"] {
+                assert_eq!(r.find(haystack), brute_force(&r, haystack), "{} in {:?}", pattern, haystack);
+            }
+        }
+    }
+
+    #[test]
+    fn reverse_and_rfind() {
+        let reversed = |pattern| KyomuRegex::compile(pattern).unwrap().reverse().to_string();
+        assert_eq!(reversed("ab*(c|de)"), "(c|ed)b*a");
+        assert_eq!(reversed("a(?=bc)"), "(?<=cb)a");
+        assert_eq!(reversed("(?<!ab)c"), "c(?!ba)");
+        for pattern in ["a(b|cd)*--a.*b", "x{2,3}y+?", "(?<=a)b(?!c)."] {
+            let r = KyomuRegex::compile(pattern).unwrap();
+            assert_eq!(r.reverse().reverse(), r);
+        }
+        let r: KyomuRegex = "a+".parse().unwrap();
+        assert_eq!(r.rfind("aa baaa"), Some(4..7));
+        assert_eq!(r.rfind("bbb"), None);
+        let r: KyomuRegex = "ab|b".parse().unwrap();
+        assert_eq!(r.rfind("abab"), Some(2..4));
+        let r: KyomuRegex = "é.".parse().unwrap();
+        assert_eq!(r.rfind("éaéb"), Some(3..6));
+        // lookarounds keep looking at the same side of the match
+        let r: KyomuRegex = "(?<=x)a|a(?=y)".parse().unwrap();
+        assert_eq!(r.rfind("xayaza"), Some(1..2));
+        assert_eq!(r.find("azaxay"), Some(4..5));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let r = KyomuRegexBuilder::new("hello.")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert!(r.whole_match("HeLLo!"));
+        assert!(!r.whole_match("HeLLp!"));
+        let r: KyomuRegex = "a(?i)b(c|d)|e".parse().unwrap();
+        assert!(r.whole_match("aBD"));
+        assert!(r.whole_match("E"));
+        assert!(!r.whole_match("ABd"));
+        let r: KyomuRegex = "(?i:k)k".parse().unwrap();
+        assert!(r.whole_match("\u{212A}k"));
+        assert!(!r.whole_match("kK"));
+    }
+
+    #[test]
+    fn whitespace_and_verbose() {
+        let r: KyomuRegex = "hello world".parse().unwrap();
+        assert!(r.whole_match("hello world"));
+        assert!(!r.whole_match("helloworld"));
+        let r = KyomuRegexBuilder::new("hello \\  world # greeting\n!")
+            .verbose(true)
+            .build()
+            .unwrap();
+        assert!(r.whole_match("hello world!"));
+        assert!(!r.whole_match("helloworld!"));
+        let r: KyomuRegex = "(?x: a b )c d".parse().unwrap();
+        assert!(r.whole_match("abc d"));
+        assert!(!r.whole_match("abcd"));
+    }
+
+    #[test]
+    fn parse_escape_sequence() {
+        let r: KyomuRegex = r"a\tb\x2a\u{3042}\n".parse().unwrap();
+        assert!(r.whole_match("a\tb*あ\n"));
+        assert!(!r.whole_match("atbx2au{3042}n"));
+        assert!(KyomuRegex::compile(r"\q").is_err());
+        assert!(KyomuRegex::compile(r"ab\").is_err());
+        // a literal dot
+        for pattern in [r"a\.b", r"a\x2Eb", r"a\u{2E}b", r"(?i)a\.b"] {
+            let r = KyomuRegex::compile(pattern).unwrap();
+            assert!(r.whole_match("a.b"), "{}", pattern);
+            assert!(!r.whole_match("axb"), "{}", pattern);
+        }
+        assert!(KyomuRegex::compile("a.b").unwrap().whole_match("axb"));
+    }
+
+    #[test]
+    fn groups_and_flags() {
+        let r: KyomuRegex = "(?:ab)+".parse().unwrap();
+        assert!(r.whole_match("abab"));
+        assert!(!r.whole_match("aba"));
+        let r: KyomuRegex = "a.b".parse().unwrap();
+        assert!(r.whole_match("a b"));
+        assert!(!r.whole_match("a\nb"));
+        let r: KyomuRegex = "(?is:a.)b(?-i)c".parse().unwrap();
+        assert!(r.whole_match("A\nbc"));
+        assert!(!r.whole_match("A\nbC"));
+        assert!(!r.whole_match("A\nBc"));
+        let r = KyomuRegexBuilder::new("(?-i:a)b.")
+            .case_insensitive(true)
+            .dot_matches_new_line(true)
+            .build()
+            .unwrap();
+        assert!(r.whole_match("aB\n"));
+        assert!(!r.whole_match("AB\n"));
+        assert!(KyomuRegex::compile("(?y:a)").is_err());
+    }
+
+    #[test]
+    fn char_class() {
+        let r: KyomuRegex = "[ab.]+[^a\n]".parse().unwrap();
+        assert!(r.whole_match("a.bx"));
+        assert!(r.whole_match("ab.."));
+        assert!(!r.whole_match("abca"));
+        assert!(!r.whole_match("ab\n"));
+        assert_eq!(KyomuRegex::compile("[^a]").unwrap(), KyomuRegex::AnyExcept(vec!['a']));
+        assert_eq!(KyomuRegex::compile(".").unwrap(), KyomuRegex::AnyExcept(vec!['\n']));
+        let r: KyomuRegex = "(?i)[^k]".parse().unwrap();
+        assert!(!r.whole_match("\u{212A}"));
+        assert!(r.whole_match("\n"));
+        assert!(KyomuRegex::compile("[]").unwrap().is_empty());
+        assert!(KyomuRegex::compile("[ab").is_err());
+        let r: KyomuRegex = r"\[a]".parse().unwrap();
+        assert!(r.whole_match("[a]"));
+    }
+
+    #[test]
+    fn lookahead() {
+        let r: KyomuRegex = "(?=.*(0|1|2|3|4|5|6|7|8|9)).*".parse().unwrap();
+        assert!(r.whole_match("abc1"));
+        assert!(!r.whole_match("abc"));
+        let r: KyomuRegex = "(a(?=b))b|ac".parse().unwrap();
+        assert!(r.whole_match("ab"));
+        assert!(r.whole_match("ac"));
+        assert!(!r.whole_match("a"));
+        let r: KyomuRegex = "(?!ab)..".parse().unwrap();
+        assert!(r.whole_match("ac"));
+        assert!(!r.whole_match("ab"));
+        let r: KyomuRegex = "x(?=yy|z)..".parse().unwrap();
+        assert!(r.whole_match("xyy"));
+        assert!(!r.whole_match("xyz"));
+        let r: KyomuRegex = "a(?=b)".parse().unwrap();
+        assert_eq!(r.find("acab"), Some(2..3));
+        assert!(KyomuRegex::compile("(?=(?=a))").is_err());
+    }
+
+    #[test]
+    fn lookbehind() {
+        let r: KyomuRegex = "(a|b)*(?<=ab)c".parse().unwrap();
+        assert!(r.whole_match("abc"));
+        assert!(r.whole_match("babc"));
+        assert!(!r.whole_match("bac"));
+        assert!(!r.whole_match("c"));
+        let r: KyomuRegex = ".*(?<!x)y".parse().unwrap();
+        assert!(r.whole_match("ay"));
+        assert!(r.whole_match("y"));
+        assert!(!r.whole_match("axy"));
+        let r: KyomuRegex = "(?<=a)b".parse().unwrap();
+        assert_eq!(r.find("bab"), Some(2..3));
+        assert_eq!(r.find("bcb"), None);
+    }
+
+    #[test]
+    fn large_bracket() {
+        let digit = "(0|1|2|3|4|5|6|7|8|9)";
+        let r: KyomuRegex = format!("{}{{1000}}", digit).parse().unwrap();
+        // the counter is decremented instead of unrolling 1000 copies
+        let KyomuRegex::Repeat(_, _, d) = &r else { panic!() };
+        assert_eq!(r.derivative('7'), KyomuRegex::Repeat(999, Some(999), d.clone()));
+        assert!(r.whole_match(&"7".repeat(1000)));
+        assert!(!r.whole_match(&"7".repeat(999)));
+        assert!(!r.whole_match(&"7".repeat(1001)));
+        let r: KyomuRegex = "a.{0,5000}b{3,}".parse().unwrap();
+        assert!(r.whole_match(&format!("a{}bbb", "x".repeat(5000))));
+        assert!(!r.whole_match(&format!("a{}bbb", "x".repeat(5001))));
+        assert!(!r.whole_match("abb"));
+    }
+
+    #[test]
+    fn ambiguous_bracket() {
+        // several counts are live at once, but they stay one interval
+        let r: KyomuRegex = "(a|aa){0,200}".parse().unwrap();
+        let d = (0..150).fold(r, |d, _| d.derivative('a'));
+        assert!(d.to_string().len() < 40, "{}", d);
+        let r: KyomuRegex = "(a|aa){0,2000}".parse().unwrap();
+        assert!(r.whole_match(&"a".repeat(1500)));
+        assert!(!r.whole_match(&"a".repeat(4001)));
+        // against the unrolled forms
+        let equivalent = |left: &str, right: &str| {
+            KyomuRegex::compile(left).unwrap().equivalent(&KyomuRegex::compile(right).unwrap())
+        };
+        assert_eq!(equivalent("(a|aa){2,5}", "a{2,10}"), Ok(()));
+        assert_eq!(equivalent("(a|ab|b){2,3}", "(a|ab|b)(a|ab|b)(a|ab|b)?"), Ok(()));
+        assert_eq!(equivalent("(a|ab|b){2,}c", "(a|ab|b)(a|ab|b)+c"), Ok(()));
+    }
+
+    #[test]
+    fn reject_bad_bracket() {
+        for pattern in ["a{2,x}", "a{1,2,3}", "a{3", "a{", "a{4,2}", "a{99999999999}"] {
+            assert!(KyomuRegex::compile(pattern).is_err(), "{}", pattern);
+        }
+        let r: KyomuRegex = r"a\{2}".parse().unwrap();
+        assert!(r.whole_match("a{2}"));
+    }
+
+    #[test]
+    fn difference() {
+        let allow: KyomuRegex = "a.*".parse().unwrap();
+        let deny: KyomuRegex = "ab.*".parse().unwrap();
+        let r = allow.difference(&deny);
+        assert!(r.whole_match("a"));
+        assert!(r.whole_match("aab"));
+        assert!(!r.whole_match("ab"));
+        assert!(!r.whole_match("abc"));
+        assert!(!r.whole_match("b"));
+        let r = allow.symmetric_difference(&"a|b".parse().unwrap());
+        assert!(r.whole_match("ac"));
+        assert!(r.whole_match("b"));
+        assert!(!r.whole_match("a"));
+        assert!(!r.whole_match("c"));
+    }
+
+    #[test]
+    fn parse_difference() {
+        let r: KyomuRegex = "a.*--ab.*".parse().unwrap();
+        assert!(r.whole_match("aab"));
+        assert!(!r.whole_match("abc"));
+        let r: KyomuRegex = "(a|b|c)--b--c".parse().unwrap();
+        assert!(r.whole_match("a"));
+        assert!(!r.whole_match("b"));
+        assert!(!r.whole_match("c"));
+        let r: KyomuRegex = "(.*--.*b.*)c".parse().unwrap();
+        assert!(r.whole_match("aac"));
+        assert!(!r.whole_match("abc"));
+        let r: KyomuRegex = r"a\-\-b".parse().unwrap();
+        assert!(r.whole_match("a--b"));
+        // unescaped, the dashes of a comment are differences
+        let r: KyomuRegex = r"<!-\-x-\->".parse().unwrap();
+        assert!(r.whole_match("<!--x-->"));
+        let r: KyomuRegex = "<!--x-->".parse().unwrap();
+        assert!(!r.whole_match("<!--x-->"));
     }
 }
@@ -1,12 +1,20 @@
 use kyomu_regex::KyomuRegex;
-use proconio::input;
 use colored::*;
 
+// Reads one line from stdin without the trailing newline
+fn read_line() -> String {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line).expect("failed to read stdin");
+    line.trim_end_matches(['\n', '\r']).to_string()
+}
+
 fn main() {
+    // --explain prints every derivative taken while matching
+    let explain = std::env::args().skip(1).any(|arg| arg == "--explain");
     println!("Input a pattern (e.g., {} ):", "a.b*(c|d)+e?f{2,3}".cyan().bold());
-    input! { pattern: String }
-    println!("{}", "Input a string to match:");
-    input! { text: String }
+    let pattern = read_line();
+    println!("Input a string to match:");
+    let text = read_line();
     match KyomuRegex::compile(&pattern) {
         Ok(regex) => {
             let start = std::time::Instant::now();
@@ -18,9 +26,23 @@ fn main() {
                 println!("{}", "Not matched.".red());
             }
             println!("Duration: {:.8?}[s]", duration.as_secs_f64());
+            if explain {
+                // the derivative taken for each character read
+                let read = std::iter::once(String::new()).chain(text.chars().map(|c| format!("{:?}", c)));
+                for (read, (regex, nullable)) in read.zip(regex.explain(&text)) {
+                    let nullable = if nullable { "ε".green() } else { "-".normal() };
+                    println!("{:>6} {} {}", read, nullable, regex);
+                }
+            }
         }
         Err(e) => {
             eprintln!("Error compiling regex: {}", e);
+            // point at the offending part of the pattern
+            let span = e.span();
+            let offset = pattern[..span.start].chars().count();
+            let width = pattern[span].chars().count().max(1);
+            eprintln!("  {}", pattern);
+            eprintln!("  {}{}", " ".repeat(offset), "^".repeat(width).red());
         }
     }
  }
@@ -0,0 +1,414 @@
+// Antimirov partial derivatives and the automaton built from them
+use crate::dfa::classes;
+use crate::{engine, s_concat, KyomuRegex, NfaError};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+impl KyomuRegex {
+    // Partial derivatives (Antimirov): a set of terms whose union is D(self).
+    // Each term is a subterm of `self` followed by others, so there are
+    // few distinct ones, unlike the Brzozowski derivatives.
+    // & ~ and lookarounds have no such form, except in sets of characters
+    // like (a|b)--b; callers check `has_partial_derivatives` first, as `Nfa::new` does.
+    pub(crate) fn partial_derivative(&self, ch: char) -> BTreeSet<KyomuRegex> {
+        use KyomuRegex::*;
+        // { p ⋅ rest | p ∈ PD(r) }
+        let then = |r: &KyomuRegex, rest: KyomuRegex| -> BTreeSet<KyomuRegex> {
+            r.partial_derivative(ch)
+                .into_iter()
+                .map(|p| s_concat(p, rest.clone()))
+                .collect()
+        };
+        match self {
+            Char(c) if *c == ch => BTreeSet::from([Eps]),
+            Any => BTreeSet::from([Eps]),
+            AnyExcept(chars) if !chars.contains(&ch) => BTreeSet::from([Eps]),
+            Char(_) | AnyExcept(_) | Eps | Empty => BTreeSet::new(),
+            And(_, _) if self.is_char_set() => match self.derivative(ch).match_eps() {
+                true => BTreeSet::from([Eps]),
+                false => BTreeSet::new(),
+            },
+            // PD(left ⋅ right) = PD(left) ⋅ right ∪ PD(right) if ε ∈ left
+            Concat(left, right) => {
+                let mut terms = then(left, (**right).clone());
+                if left.match_eps() {
+                    terms.extend(right.partial_derivative(ch));
+                }
+                terms
+            }
+            Or(left, right) => {
+                let mut terms = left.partial_derivative(ch);
+                terms.extend(right.partial_derivative(ch));
+                terms
+            }
+            // PD(r*) = PD(r+) = PD(r) ⋅ r*
+            Star(r) | Plus(r) => then(r, Star(r.clone())),
+            Question(r) | Lazy(r) => r.partial_derivative(ch),
+            Repeat(min, max, r) => match max {
+                Some(max) if min > max => BTreeSet::new(),
+                Some(0) => BTreeSet::new(),
+                // PD(r{min, max}) = PD(r) ⋅ r{min-1, max-1}, as in `derivative`
+                _ => {
+                    let lower = if r.match_eps() { 0 } else { *min };
+                    then(
+                        r,
+                        Repeat(lower.saturating_sub(1), max.map(|max| max - 1), r.clone()),
+                    )
+                }
+            },
+            And(_, _)
+            | Not(_)
+            | LookAhead(_)
+            | NegLookAhead(_)
+            | LookBehind(_, _)
+            | NegLookBehind(_, _) => {
+                unreachable!("no partial derivatives of {:?}", self)
+            }
+        }
+    }
+
+    // `partial_derivative` with each term charged for the lazy iterations it
+    // starts, following `costed_derivative` case by case
+    pub(crate) fn costed_partial_derivative(&self, ch: char) -> BTreeSet<(KyomuRegex, u32)> {
+        use KyomuRegex::*;
+        if !self.is_lazy() {
+            return self.partial_derivative(ch).into_iter().map(|p| (p, 0)).collect();
+        }
+        // { p ⋅ rest | p ∈ PD(r) }, with `extra` added to each charge
+        let then = |r: &KyomuRegex, rest: KyomuRegex, extra: u32| -> BTreeSet<(KyomuRegex, u32)> {
+            r.costed_partial_derivative(ch)
+                .into_iter()
+                .map(|(p, cost)| (s_concat(p, rest.clone()), cost + extra))
+                .collect()
+        };
+        let b = |r: KyomuRegex| Box::new(r);
+        match self {
+            Concat(left, right) => {
+                let mut terms = then(left, (**right).clone(), 0);
+                if left.match_eps() {
+                    terms.extend(right.costed_partial_derivative(ch));
+                }
+                terms
+            }
+            Or(left, right) => {
+                let mut terms = left.costed_partial_derivative(ch);
+                terms.extend(right.costed_partial_derivative(ch));
+                terms
+            }
+            Star(r) => then(r, Star(r.clone()), 0),
+            Plus(r) => Concat(r.clone(), b(Star(r.clone()))).costed_partial_derivative(ch),
+            Question(r) => r.costed_partial_derivative(ch),
+            Repeat(min, max, r) => match max {
+                Some(max) if min > max => BTreeSet::new(),
+                Some(0) => BTreeSet::new(),
+                _ if *min > 0 => {
+                    let rest = Repeat(min - 1, max.map(|max| max - 1), r.clone());
+                    Concat(r.clone(), b(rest)).costed_partial_derivative(ch)
+                }
+                _ => then(r, Repeat(0, max.map(|max| max - 1), r.clone()), 0),
+            },
+            Lazy(q) => match &**q {
+                Star(r) => then(r, self.clone(), 1),
+                // the first iteration is not up to the quantifier
+                Plus(r) => Concat(r.clone(), b(Lazy(b(Star(r.clone()))))).costed_partial_derivative(ch),
+                Question(r) => then(r, Eps, 1),
+                Repeat(min, max, r) => match max {
+                    Some(max) if min > max => BTreeSet::new(),
+                    Some(0) => BTreeSet::new(),
+                    _ if *min > 0 => {
+                        let rest = Lazy(b(Repeat(min - 1, max.map(|max| max - 1), r.clone())));
+                        Concat(r.clone(), b(rest)).costed_partial_derivative(ch)
+                    }
+                    _ => then(r, Lazy(b(Repeat(0, max.map(|max| max - 1), r.clone()))), 1),
+                },
+                q => q.costed_partial_derivative(ch),
+            },
+            // sets of characters have no lazy quantifiers
+            _ => unreachable!("no partial derivatives of {:?}", self),
+        }
+    }
+
+    // Whether `self` matches single characters only, like (a|b|c)--b.
+    // Such a set is read in one step, whatever operators it is made of.
+    fn is_char_set(&self) -> bool {
+        use KyomuRegex::*;
+        match self {
+            Char(_) | Any | AnyExcept(_) => true,
+            Or(left, right) => left.is_char_set() && right.is_char_set(),
+            And(left, right) => left.is_char_set() && matches!(&**right, Not(r) if r.is_char_set()),
+            _ => false,
+        }
+    }
+
+    // whether `partial_derivative` can take apart every subterm
+    pub(crate) fn has_partial_derivatives(&self) -> bool {
+        use KyomuRegex::*;
+        match self {
+            Char(_) | Any | AnyExcept(_) | Eps | Empty => true,
+            And(_, _) => self.is_char_set(),
+            Concat(left, right) | Or(left, right) => {
+                left.has_partial_derivatives() && right.has_partial_derivatives()
+            }
+            Star(r) | Plus(r) | Question(r) | Repeat(_, _, r) | Lazy(r) => {
+                r.has_partial_derivatives()
+            }
+            Not(_) | LookAhead(_) | NegLookAhead(_) | LookBehind(_, _) | NegLookBehind(_, _) => {
+                false
+            }
+        }
+    }
+}
+
+// Transition table over the partial derivatives of a regex; state 0 is the regex.
+// Each transition is charged as in `costed_partial_derivative`.
+#[derive(Debug, Clone)]
+struct Table {
+    alphabet: Vec<char>, // sorted literals; anything else falls in the last class
+    states: Vec<KyomuRegex>,
+    transitions: Vec<Vec<Vec<(usize, u32)>>>, // state -> class -> (state, charge)
+}
+
+impl Table {
+    fn new(regex: &KyomuRegex) -> Self {
+        let mut literals = BTreeSet::new();
+        regex.literals(&mut literals);
+        let alphabet: Vec<char> = literals.into_iter().collect();
+        let representatives: Vec<char> = classes(&alphabet)
+            .iter()
+            .map(|c| c.representative())
+            .collect();
+        let mut table = Table {
+            alphabet,
+            states: vec![regex.clone()],
+            transitions: vec![],
+        };
+        let mut index = HashMap::from([(regex.clone(), 0)]);
+        let mut queue = VecDeque::from([0]);
+        while let Some(state) = queue.pop_front() {
+            let mut row = Vec::with_capacity(representatives.len());
+            for &ch in &representatives {
+                let mut targets = Vec::new();
+                for (next, cost) in table.states[state].costed_partial_derivative(ch) {
+                    let id = *index.entry(next.clone()).or_insert_with(|| {
+                        table.states.push(next);
+                        queue.push_back(table.states.len() - 1);
+                        table.states.len() - 1
+                    });
+                    targets.push((id, cost));
+                }
+                row.push(targets);
+            }
+            table.transitions.push(row);
+        }
+        table
+    }
+    fn class(&self, ch: char) -> usize {
+        self.alphabet
+            .binary_search(&ch)
+            .unwrap_or(self.alphabet.len())
+    }
+    // the states reached from `set` by reading `ch`, whatever the charges
+    fn step(&self, set: &[usize], ch: char) -> Vec<usize> {
+        let class = self.class(ch);
+        let mut next: Vec<usize> = set
+            .iter()
+            .flat_map(|&s| self.transitions[s][class].iter().map(|&(next, _)| next))
+            .collect();
+        next.sort();
+        next.dedup();
+        next
+    }
+    fn accepts(&self, set: &[usize]) -> bool {
+        set.iter().any(|&s| self.states[s].match_eps())
+    }
+}
+
+// a table only reads, so a shared one will do
+impl engine::Scan for &Table {
+    type State = Vec<usize>;
+    fn step(&mut self, set: &Vec<usize>, ch: char) -> Vec<usize> {
+        Table::step(self, set, ch)
+    }
+    fn accepts(&mut self, set: &Vec<usize>, _rest: &str) -> bool {
+        Table::accepts(self, set)
+    }
+    fn is_dead(&self, set: &Vec<usize>) -> bool {
+        set.is_empty()
+    }
+}
+
+// one state at a time, as lazy quantifiers weigh each one apart
+impl engine::CostedScan for &Table {
+    type State = usize;
+    fn costed_step(&mut self, state: &usize, ch: char) -> Vec<(usize, u32)> {
+        self.transitions[*state][self.class(ch)].clone()
+    }
+    fn accepts(&mut self, state: &usize, _rest: &str) -> bool {
+        self.states[*state].match_eps()
+    }
+}
+
+// Nondeterministic automaton on the partial derivatives of a regex, simulated
+// on sets of states. It has at most one state per character of the pattern
+// (times the bounds of repetitions), where the derivative DFA may have
+// exponentially many.
+#[derive(Debug, Clone)]
+pub struct Nfa {
+    forward: Table,
+    backward: Table, // on `engine::start_scan`
+    // whether the regex has lazy quantifiers, whose matches are ranked by
+    // `engine::preferred_end`
+    lazy: bool,
+}
+
+impl Nfa {
+    // Fails on & ~ (other than in sets of characters) and lookarounds,
+    // which partial derivatives do not cover.
+    pub fn new(regex: &KyomuRegex) -> Result<Self, NfaError> {
+        if !regex.has_partial_derivatives() {
+            return Err(NfaError);
+        }
+        Ok(Nfa {
+            forward: Table::new(regex),
+            backward: Table::new(&engine::start_scan(regex)),
+            lazy: regex.is_lazy(),
+        })
+    }
+    // the regex each state stands for
+    pub fn states(&self) -> &[KyomuRegex] {
+        &self.forward.states
+    }
+    pub fn whole_match(&self, input: &str) -> bool {
+        let set = input
+            .chars()
+            .fold(vec![0], |set, ch| self.forward.step(&set, ch));
+        self.forward.accepts(&set)
+    }
+    // Same result as `KyomuRegex::find`
+    pub fn find(&self, haystack: &str) -> Option<std::ops::Range<usize>> {
+        let start = engine::leftmost_start(&mut &self.backward, vec![0], haystack)?;
+        if self.lazy {
+            let end = engine::preferred_end(&mut &self.forward, 0, haystack, start);
+            return end.map(|end| start..end);
+        }
+        let end = engine::longest_end(&mut &self.forward, vec![0], haystack, start);
+        end.map(|end| start..end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rng;
+
+    #[test]
+    fn partial_derivatives() {
+        let r = KyomuRegex::compile("a*b|ac").unwrap();
+        let terms: Vec<String> = r
+            .partial_derivative('a')
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(terms, ["c", "a*b"]);
+        assert_eq!(r.partial_derivative('b'), [KyomuRegex::Eps].into());
+        assert!(r.partial_derivative('c').is_empty());
+    }
+
+    #[test]
+    fn linear_states() {
+        let r = KyomuRegex::compile("(a|b)*a(a|b){20}").unwrap();
+        let nfa = Nfa::new(&r).unwrap();
+        assert!(nfa.states().len() <= 25, "{} states", nfa.states().len());
+        let mut rng = Rng::new(1);
+        for _ in 0..100 {
+            let input: String = (0..24).map(|_| ['a', 'b'][rng.below(2)]).collect();
+            assert_eq!(
+                nfa.whole_match(&input),
+                r.whole_match(&input),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn agrees_with_derivatives() {
+        let patterns = [
+            "a(b|c)*d?",
+            "(ab|b)*a",
+            "<.+>",
+            "<.+?>",
+            "x{2,3}y*?",
+            "a+?b+",
+            "<.+?>x*",
+            "()",
+            "é+",
+            "(a|b)*abb",
+            "(?i)k.",
+        ];
+        let haystacks = [
+            "",
+            "abcbd",
+            "x<a><b>y",
+            "xxxyy",
+            "bbaba",
+            "aébé",
+            "xyz",
+            "<a>xx",
+            "xyz",
+            "<\n>K\u{212A}\n",
+        ];
+        for pattern in patterns {
+            let r = KyomuRegex::compile(pattern).unwrap();
+            let nfa = Nfa::new(&r).unwrap();
+            for haystack in haystacks {
+                assert_eq!(
+                    nfa.whole_match(haystack),
+                    r.whole_match(haystack),
+                    "{} on {:?}",
+                    pattern,
+                    haystack
+                );
+                assert_eq!(
+                    nfa.find(haystack),
+                    r.find(haystack),
+                    "{} in {:?}",
+                    pattern,
+                    haystack
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn lazy_quantifiers() {
+        let patterns = [
+            "(a|ab)+?b*",
+            "a*?(ab)?b*",
+            "b{1,3}?a*",
+            "(b+?a|b)*",
+            "(a*?b)+c?",
+            "a??b??a",
+            "(a?){2}?b",
+            "(a|b)*?a(a|b){2}",
+        ];
+        for pattern in patterns {
+            let r = KyomuRegex::compile(pattern).unwrap();
+            let nfa = Nfa::new(&r).unwrap();
+            for haystack in crate::tests::strings_up_to(&['a', 'b', 'c'], 5) {
+                assert_eq!(nfa.find(&haystack), r.find(&haystack), "{} in {:?}", pattern, haystack);
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        assert_eq!(
+            Nfa::new(&KyomuRegex::compile("a*--b").unwrap()).unwrap_err(),
+            NfaError
+        );
+        assert_eq!(NfaError.to_string(), "The NFA engine does not support & ~ or lookarounds");
+        // a set of characters is fine
+        assert!(Nfa::new(&KyomuRegex::compile("(a|b|c)--b").unwrap()).is_ok());
+        assert!(Nfa::new(&KyomuRegex::compile("(?=a)a").unwrap()).is_err());
+    }
+}
@@ -1,74 +1,152 @@
+use crate::error::{CompileError, RepetitionError, Span, TokenKind};
 use crate::lex::*;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[allow(clippy::enum_variant_names)]
 pub enum Node {
     NdChar(char),
+    NdAny,
+    NdClass(bool, Vec<char>), // [abc], or [^abc] when negated
     NdEps,
     NdStar(Box<Node>),
     NdPlus(Box<Node>),
     NdOr(Box<Node>, Box<Node>),
     NdQuestion(Box<Node>),
     NdConcat(Box<Node>, Box<Node>),
-    NdBracket (u32, Option<u32>, Box<Node>),
+    NdRepeat(u32, Option<u32>, Box<Node>),
+    NdDiff(Box<Node>, Box<Node>),
+    NdLazy(Box<Node>), // lazy variant of the quantifier inside
+    NdGroup(FlagDelta, Box<Node>), // flags changed for the inner node
+    NdLookAhead(Box<Node>),
+    NdNegLookAhead(Box<Node>),
+    NdLookBehind(Box<Node>),
+    NdNegLookBehind(Box<Node>),
 }
 pub struct Parser<'a> {
     lexer: Lexer<'a>,
     look: Token,
+    look_span: Span,
+    inline: FlagDelta, // flags changed by (?flags) so far in the current group
+    in_lookaround: bool,
 }
-type Result<T> = std::result::Result<T, String>;
-
-fn error_msg(expected: &[Token], actual: &Token) -> String {
-    let expected = expected
-        .iter()
-        .map(|t| format!("'{}'", t))
-        .collect::<Vec<_>>()
-        .join(", ");
-    let actual = match actual {
-        Token::TkChar(c) => format!("'{}'", c),
-        _ => format!("'{}'", actual),
-    };
-    format!("Expected one of [{:?}], found {}", expected, actual)
-}
+type Result<T> = std::result::Result<T, CompileError>;
 
 impl Parser<'_> {
-    pub fn new(mut lexer: Lexer) -> Parser{
-        let node = lexer.next_token();
-        Parser {
+    pub fn new(mut lexer: Lexer) -> Result<Parser> {
+        let node = lexer.next_token()?;
+        Ok(Parser {
+            look_span: lexer.span(),
             lexer,
             look: node,
-        }
+            inline: FlagDelta::default(),
+            in_lookaround: false,
+        })
     }
 
     fn match_next(&mut self, token: Token) -> Result<()> {
         if self.look == token {
-            self.look = self.lexer.next_token();
+            self.look = self.lexer.next_token()?;
+            self.look_span = self.lexer.span();
             Ok(())
         } else {
-            Err(error_msg(&[token], &self.look))
+            Err(self.unexpected(&[token.into()]))
+        }
+    }
+
+    fn unexpected(&self, expected: &[TokenKind]) -> CompileError {
+        CompileError::UnexpectedToken {
+            expected: expected.to_vec(),
+            found: self.look.into(),
+            span: self.look_span.clone(),
+        }
+    }
+
+    // the ')' closing the group opened at `open`
+    fn close_paren(&mut self, open: Span) -> Result<()> {
+        match self.look {
+            Token::TkEps => Err(CompileError::UnbalancedParen { span: open }),
+            _ => self.match_next(Token::TkRparen),
         }
     }
 
     fn factor(&mut self) -> Result<Node> {
+        let open = self.look_span.clone();
         match &self.look {
             Token::TkLparen => {
                 self.match_next(Token::TkLparen)?;
-                let node = self.sub_expr();
-                self.match_next(Token::TkRparen)?;
-                node
+                let node = self.group()?;
+                self.close_paren(open)?;
+                Ok(node)
+            }
+            Token::TkFlagGroup(delta) => {
+                let delta = *delta;
+                self.match_next(Token::TkFlagGroup(delta))?;
+                let node = self.group()?;
+                self.close_paren(open)?;
+                if delta == FlagDelta::default() {
+                    // (?:...) only groups
+                    Ok(node)
+                } else {
+                    Ok(Node::NdGroup(delta, Box::new(node)))
+                }
             }
             Token::TkChar(c) => {
                 let node = Node::NdChar(*c);
                 self.match_next(Token::TkChar(*c))?;
                 Ok(node)
             }
-            _ => Err(error_msg(&[Token::TkLparen, Token::TkChar('_')], &self.look)),
+            Token::TkAny => {
+                self.match_next(Token::TkAny)?;
+                Ok(Node::NdAny)
+            }
+            Token::TkClassOpen(negated) => {
+                let negated = *negated;
+                self.match_next(Token::TkClassOpen(negated))?;
+                // the lexer gives nothing but characters until ']'
+                let mut chars = Vec::new();
+                while let Token::TkChar(c) = self.look {
+                    chars.push(c);
+                    self.match_next(Token::TkChar(c))?;
+                }
+                if self.look == Token::TkEps {
+                    return Err(CompileError::UnbalancedBracket { span: open });
+                }
+                self.match_next(Token::TkClassClose)?;
+                Ok(Node::NdClass(negated, chars))
+            }
+            Token::TkLookAhead | Token::TkNegLookAhead | Token::TkLookBehind | Token::TkNegLookBehind => {
+                let token = self.look;
+                if self.in_lookaround {
+                    return Err(CompileError::NestedLookaround { span: open });
+                }
+                self.match_next(token)?;
+                self.in_lookaround = true;
+                let node = Box::new(self.group()?);
+                self.in_lookaround = false;
+                self.close_paren(open)?;
+                Ok(match token {
+                    Token::TkLookAhead => Node::NdLookAhead(node),
+                    Token::TkNegLookAhead => Node::NdNegLookAhead(node),
+                    Token::TkLookBehind => Node::NdLookBehind(node),
+                    _ => Node::NdNegLookBehind(node),
+                })
+            }
+            _ => Err(self.unexpected(&[TokenKind::Lparen, TokenKind::AnyChar])),
         }
     }
 
+    // inline flags do not leak out of the enclosing group
+    fn group(&mut self) -> Result<Node> {
+        let outer = std::mem::take(&mut self.inline);
+        let node = self.diff_expr();
+        self.inline = outer;
+        node
+    }
+
     fn quantifier(&mut self) -> Result<Node> {
         let factor = self.factor();
-        let token = self.look.clone();
-        match token {
+        let token = self.look;
+        let quantified = match token {
             Token::TkStar => {
                 self.match_next(Token::TkStar)?;
                 Ok(Node::NdStar(Box::new(factor?)))
@@ -81,38 +159,88 @@ impl Parser<'_> {
                 self.match_next(Token::TkQuestion)?;
                 Ok(Node::NdQuestion(Box::new(factor?)))
             }
-            Token::TkBracket(min, max) => {
-                self.match_next(Token::TkBracket(min, max))?;
-                Ok(Node::NdBracket(min, max, Box::new(factor?)))
+            Token::TkRepeat(min, max) => {
+                self.match_next(Token::TkRepeat(min, max))?;
+                Ok(Node::NdRepeat(min, max, Box::new(factor?)))
+            }
+            Token::TkLazyStar => {
+                self.match_next(Token::TkLazyStar)?;
+                Ok(Node::NdLazy(Box::new(Node::NdStar(Box::new(factor?)))))
+            }
+            Token::TkLazyPlus => {
+                self.match_next(Token::TkLazyPlus)?;
+                Ok(Node::NdLazy(Box::new(Node::NdPlus(Box::new(factor?)))))
+            }
+            Token::TkLazyQuestion => {
+                self.match_next(Token::TkLazyQuestion)?;
+                Ok(Node::NdLazy(Box::new(Node::NdQuestion(Box::new(factor?)))))
             }
-            _ => factor,
+            Token::TkLazyRepeat(min, max) => {
+                self.match_next(Token::TkLazyRepeat(min, max))?;
+                Ok(Node::NdLazy(Box::new(Node::NdRepeat(min, max, Box::new(factor?)))))
+            }
+            _ => return factor,
+        };
+        // a**, x{2}{3}: the second would apply to a quantifier, not to a factor
+        if is_quantifier(self.look) {
+            return Err(CompileError::BadRepetition {
+                kind: RepetitionError::Repeated,
+                span: self.look_span.clone(),
+            });
         }
+        quantified
+    }
+
+    fn starts_seq(&self) -> bool {
+        matches!(
+            self.look,
+            Token::TkLparen
+                | Token::TkChar(_)
+                | Token::TkAny
+                | Token::TkClassOpen(_)
+                | Token::TkFlagGroup(_)
+                | Token::TkSetFlags(_)
+                | Token::TkLookAhead
+                | Token::TkNegLookAhead
+                | Token::TkLookBehind
+                | Token::TkNegLookBehind
+        )
     }
 
     fn sub_seq(&mut self) -> Result<Node> {
+        if let Token::TkSetFlags(delta) = self.look {
+            // (?flags) applies to the rest of the sequence
+            self.match_next(Token::TkSetFlags(delta))?;
+            self.inline = self.inline.then(delta);
+            return Ok(Node::NdGroup(delta, Box::new(self.seq()?)));
+        }
         let quantifier = self.quantifier();
-        match &self.look {
-            Token::TkLparen | Token::TkChar(_) => {
-                Ok(
-                    Node::NdConcat(
-                        Box::new(quantifier?),
-                        Box::new(self.sub_seq()?),
-                    ),
-                )
-            }
-            _ => quantifier,
+        if self.starts_seq() {
+            Ok(
+                Node::NdConcat(
+                    Box::new(quantifier?),
+                    Box::new(self.sub_seq()?),
+                ),
+            )
+        } else {
+            quantifier
         }
     }
 
     fn seq(&mut self) -> Result<Node> {
-        match &self.look {
-            Token::TkLparen | Token::TkChar(_) => self.sub_seq(),
-            _ => Ok(Node::NdEps),
+        if self.starts_seq() {
+            self.sub_seq()
+        } else {
+            Ok(Node::NdEps)
         }
     }
 
     fn sub_expr(&mut self) -> Result<Node> {
-        let seq = self.seq()?;
+        // (?flags) earlier in the group also applies to this alternative
+        let seq = match self.inline {
+            inline if inline == FlagDelta::default() => self.seq()?,
+            inline => Node::NdGroup(inline, Box::new(self.seq()?)),
+        };
         match &self.look {
             Token::TkOr => {
                 self.match_next(Token::TkOr)?;
@@ -127,10 +255,22 @@ impl Parser<'_> {
         }
     }
 
+    fn diff_expr(&mut self) -> Result<Node> {
+        let mut node = self.sub_expr()?;
+        while self.look == Token::TkDiff {
+            self.match_next(Token::TkDiff)?;
+            node = Node::NdDiff(Box::new(node), Box::new(self.sub_expr()?));
+        }
+        Ok(node)
+    }
+
     fn expr(&mut self) -> Result<Node> {
-        let expr = self.sub_expr();
+        let expr = self.diff_expr()?;
+        if self.look == Token::TkRparen {
+            return Err(CompileError::UnbalancedParen { span: self.look_span.clone() });
+        }
         self.match_next(Token::TkEps)?;
-        expr
+        Ok(expr)
     }
 
     pub fn parse(&mut self) -> Result<Node> {
@@ -138,14 +278,24 @@ impl Parser<'_> {
     }
 }
 
+// a token quantifying the factor before it
+fn is_quantifier(token: Token) -> bool {
+    use Token::*;
+    matches!(
+        token,
+        TkStar | TkPlus | TkQuestion | TkRepeat(..) | TkLazyStar | TkLazyPlus | TkLazyQuestion | TkLazyRepeat(..)
+    )
+}
+
 #[cfg(test)]
 mod tests {
+    use crate::error::RepetitionError;
     use crate::lex::*;
     use crate::parse::*;
 
     #[test]
     fn expression() {
-        let mut parser = Parser::new(Lexer::new(r"a|(bc)*"));
+        let mut parser = Parser::new(Lexer::new(r"a|(bc)*")).unwrap();
         assert_eq!(
             parser.expr(),
             Ok(Node::NdOr(
@@ -160,7 +310,7 @@ mod tests {
 
     #[test]
     fn plus_operator() {
-        let mut parse = Parser::new(Lexer::new(r"a+b?"));
+        let mut parse = Parser::new(Lexer::new(r"a+b?")).unwrap();
         assert_eq!(
             parse.expr(),
             Ok(Node::NdConcat(
@@ -172,16 +322,153 @@ mod tests {
 
     #[test]
     fn bracket_operator() {
-        let mut parse = Parser::new(Lexer::new(r"a{2,3}b{0,}c{4}"));
+        let mut parse = Parser::new(Lexer::new(r"a{2,3}b{0,}c{4}")).unwrap();
+        assert_eq!(
+            parse.expr(),
+            Ok(Node::NdConcat(
+                Box::new(Node::NdRepeat(2, Some(3), Box::new(Node::NdChar('a')))),
+                Box::new(Node::NdConcat(
+                    Box::new(Node::NdRepeat(0, None, Box::new(Node::NdChar('b')))),
+                    Box::new(Node::NdRepeat(4, Some(4), Box::new(Node::NdChar('c'))))
+                ))
+            ))
+        );
+    }
+
+    #[test]
+    fn diff_operator() {
+        let mut parse = Parser::new(Lexer::new(r"a|b--a--(c)")).unwrap();
+        assert_eq!(
+            parse.expr(),
+            Ok(Node::NdDiff(
+                Box::new(Node::NdDiff(
+                    Box::new(Node::NdOr(
+                        Box::new(Node::NdChar('a')),
+                        Box::new(Node::NdChar('b'))
+                    )),
+                    Box::new(Node::NdChar('a'))
+                )),
+                Box::new(Node::NdChar('c'))
+            ))
+        );
+    }
+
+    #[test]
+    fn lazy_operator() {
+        let mut parse = Parser::new(Lexer::new(r"a+?b{2,}?")).unwrap();
         assert_eq!(
             parse.expr(),
             Ok(Node::NdConcat(
-                Box::new(Node::NdBracket(2, Some(3), Box::new(Node::NdChar('a')))),
+                Box::new(Node::NdLazy(Box::new(Node::NdPlus(Box::new(Node::NdChar('a')))))),
+                Box::new(Node::NdLazy(Box::new(Node::NdRepeat(
+                    2,
+                    None,
+                    Box::new(Node::NdChar('b'))
+                ))))
+            ))
+        );
+    }
+
+    #[test]
+    fn char_classes() {
+        let mut parse = Parser::new(Lexer::new(r"[a.\]][^]x]")).unwrap();
+        assert_eq!(
+            parse.expr(),
+            Ok(Node::NdConcat(
+                Box::new(Node::NdClass(false, vec!['a', '.', ']'])),
+                Box::new(Node::NdConcat(
+                    Box::new(Node::NdClass(true, vec![])),
+                    Box::new(Node::NdConcat(
+                        Box::new(Node::NdChar('x')),
+                        Box::new(Node::NdChar(']'))
+                    ))
+                ))
+            ))
+        );
+    }
+
+    #[test]
+    fn flag_groups() {
+        let ci = FlagDelta {
+            on: Flags { case_insensitive: true, ..Flags::default() },
+            ..FlagDelta::default()
+        };
+        let mut parse = Parser::new(Lexer::new(r"a(?i)b|(?i:c)(?:d)")).unwrap();
+        assert_eq!(
+            parse.expr(),
+            Ok(Node::NdOr(
                 Box::new(Node::NdConcat(
-                    Box::new(Node::NdBracket(0, Some(0), Box::new(Node::NdChar('b')))),
-                    Box::new(Node::NdBracket(4, None, Box::new(Node::NdChar('c'))))
+                    Box::new(Node::NdChar('a')),
+                    Box::new(Node::NdGroup(ci, Box::new(Node::NdChar('b'))))
+                )),
+                Box::new(Node::NdGroup(
+                    ci,
+                    Box::new(Node::NdConcat(
+                        Box::new(Node::NdGroup(ci, Box::new(Node::NdChar('c')))),
+                        Box::new(Node::NdChar('d'))
+                    ))
                 ))
             ))
         );
     }
+
+    #[test]
+    fn errors_with_span() {
+        let parse = |pattern| Parser::new(Lexer::new(pattern)).and_then(|mut p| p.expr());
+        assert_eq!(parse("ab(c"), Err(CompileError::UnbalancedParen { span: 2..3 }));
+        assert_eq!(parse("a)b"), Err(CompileError::UnbalancedParen { span: 1..2 }));
+        assert_eq!(
+            parse("ab|*"),
+            Err(CompileError::UnexpectedToken {
+                expected: vec![TokenKind::End],
+                found: TokenKind::Star,
+                span: 3..4
+            })
+        );
+        assert_eq!(
+            parse("ab|*").unwrap_err().to_string(),
+            "Expected one of [end of pattern], found '*' at 3..4"
+        );
+        assert_eq!(
+            parse("a|{2,}?").unwrap_err().to_string(),
+            "Expected one of [end of pattern], found '{2,}?' at 2..7"
+        );
+        assert_eq!(
+            parse(r"a\q"),
+            Err(CompileError::InvalidEscape { escape: r"\q".to_string(), span: 1..3 })
+        );
+        assert_eq!(parse("a(?iy:b)"), Err(CompileError::UnknownFlag { flag: 'y', span: 4..5 }));
+        assert_eq!(parse("(?i-i)a"), Err(CompileError::ConflictingFlag { flag: 'i', span: 4..5 }));
+        assert_eq!(parse("(?si-xs:a)"), Err(CompileError::ConflictingFlag { flag: 's', span: 6..7 }));
+        assert!(parse("(?i-s)(?-i)a").is_ok());
+        assert_eq!(parse("a(?)b"), Err(CompileError::MissingFlag { span: 3..4 }));
+        assert_eq!(parse("(?-)a"), Err(CompileError::MissingFlag { span: 3..4 }));
+        assert_eq!(parse("(?s-:a)"), Err(CompileError::MissingFlag { span: 4..5 }));
+        assert_eq!(parse("(?)").unwrap_err().to_string(), "Expected a flag at 2..3");
+        assert!(parse("(?:a)(?-s:b)").is_ok());
+        assert_eq!(
+            parse("a{2,x}"),
+            Err(CompileError::BadRepetition { kind: RepetitionError::InvalidContent, span: 1..5 })
+        );
+        assert_eq!(
+            parse("x{2}{3}"),
+            Err(CompileError::BadRepetition { kind: RepetitionError::Repeated, span: 4..7 })
+        );
+        assert_eq!(
+            parse("ab*{1,}?").unwrap_err().to_string(),
+            "Malformed repetition: a quantifier cannot be repeated at 3..8"
+        );
+        assert!(parse("(x{2}){3}").is_ok());
+        for (pattern, span) in [("a**", 2..3), ("a{2}*", 4..5), ("a+?{3}", 3..6), ("a*??", 3..4), ("a?+", 2..3)] {
+            let error = CompileError::BadRepetition { kind: RepetitionError::Repeated, span };
+            assert_eq!(parse(pattern), Err(error), "{}", pattern);
+        }
+        assert_eq!(parse("(?=(?!a))"), Err(CompileError::NestedLookaround { span: 3..6 }));
+        assert_eq!(parse("a[^bc"), Err(CompileError::UnbalancedBracket { span: 1..3 }));
+        assert_eq!(parse("[a-z]"), Err(CompileError::ClassRange { span: 2..3 }));
+        assert_eq!(
+            parse("x[ab-]y[^a-b]").unwrap_err().to_string(),
+            r"Ranges in character classes are not supported; write \- for '-' at 10..11"
+        );
+    }
 }
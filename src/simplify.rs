@@ -0,0 +1,356 @@
+// Rewriting by the laws of regular algebra
+use crate::{alternatives, conjuncts, factors, KyomuRegex};
+use crate::KyomuRegex::*;
+
+// the alternatives of a chain of |, without duplicates: r | r = r
+fn distinct_alternatives(r: KyomuRegex, out: &mut Vec<KyomuRegex>) {
+    let mut terms = Vec::new();
+    alternatives(r, &mut terms);
+    for r in terms {
+        if !out.contains(&r) {
+            out.push(r);
+        }
+    }
+}
+
+// the conjuncts of a chain of &, without duplicates: r & r = r
+fn distinct_conjuncts(r: KyomuRegex, out: &mut Vec<KyomuRegex>) {
+    let mut terms = Vec::new();
+    conjuncts(r, &mut terms);
+    for r in terms {
+        if !out.contains(&r) {
+            out.push(r);
+        }
+    }
+}
+
+// joins `terms` with `op` to the right
+fn join(
+    terms: Vec<KyomuRegex>,
+    op: fn(Box<KyomuRegex>, Box<KyomuRegex>) -> KyomuRegex,
+) -> Option<KyomuRegex> {
+    terms
+        .into_iter()
+        .rev()
+        .reduce(|acc, r| op(Box::new(r), Box::new(acc)))
+}
+
+// Whether r matches ε wherever it stands and whatever follows. `delta`
+// runs lookbehinds on no input, which says nothing of other places, so
+// they count as not nullable: c(?<!c)? is not c(?<!c).
+fn nullable(r: &KyomuRegex) -> bool {
+    !has_lookbehind(r) && r.delta() == Eps
+}
+
+fn has_lookbehind(r: &KyomuRegex) -> bool {
+    match r {
+        LookBehind(_, _) | NegLookBehind(_, _) => true,
+        Char(_) | Any | AnyExcept(_) | Eps | Empty | LookAhead(_) | NegLookAhead(_) => false,
+        Concat(left, right) | Or(left, right) | And(left, right) => {
+            has_lookbehind(left) || has_lookbehind(right)
+        }
+        Star(r) | Plus(r) | Question(r) | Repeat(_, _, r) | Not(r) | Lazy(r) => has_lookbehind(r),
+    }
+}
+
+fn concat(left: KyomuRegex, right: KyomuRegex) -> KyomuRegex {
+    let mut terms = Vec::new();
+    factors(left, &mut terms);
+    factors(right, &mut terms);
+    // ∅ ⋅ r = r ⋅ ∅ = ∅
+    if terms.contains(&Empty) {
+        return Empty;
+    }
+    // ε ⋅ r = r ⋅ ε = r
+    terms.retain(|r| *r != Eps);
+    // r ⋅ r* = r* ⋅ r = r+
+    let mut i = 0;
+    while i + 1 < terms.len() {
+        let plus = match (&terms[i], &terms[i + 1]) {
+            (r, Star(s)) | (Star(s), r) if **s == *r => Some(Plus(s.clone())),
+            _ => None,
+        };
+        match plus {
+            Some(plus) => {
+                terms.splice(i..i + 2, [plus]);
+            }
+            None => i += 1,
+        }
+    }
+    join(terms, Concat).unwrap_or(Eps)
+}
+
+fn or(terms: Vec<KyomuRegex>) -> KyomuRegex {
+    // a ⋅ r | a ⋅ s = a ⋅ (r | s)
+    let mut groups: Vec<(KyomuRegex, Vec<KyomuRegex>)> = Vec::new();
+    for term in terms {
+        let mut chain = Vec::new();
+        factors(term, &mut chain);
+        let head = chain.remove(0);
+        let tail = join(chain, Concat).unwrap_or(Eps);
+        match groups.iter_mut().find(|(h, _)| *h == head) {
+            Some((_, tails)) => tails.push(tail),
+            None => groups.push((head, vec![tail])),
+        }
+    }
+    let mut terms = Vec::new();
+    for (head, tails) in groups {
+        let tail = match tails.len() {
+            1 => tails.into_iter().next().unwrap(),
+            _ => {
+                let mut alts = Vec::new();
+                tails.into_iter().for_each(|t| distinct_alternatives(t, &mut alts));
+                or(alts)
+            }
+        };
+        distinct_alternatives(concat(head, tail), &mut terms);
+    }
+    // r ⋅ a | s ⋅ a = (r | s) ⋅ a
+    let mut groups: Vec<(KyomuRegex, Vec<KyomuRegex>)> = Vec::new();
+    for term in terms {
+        let mut chain = Vec::new();
+        factors(term, &mut chain);
+        let last = chain.pop().unwrap();
+        let init = join(chain, Concat).unwrap_or(Eps);
+        match groups.iter_mut().find(|(l, _)| *l == last) {
+            Some((_, inits)) => inits.push(init),
+            None => groups.push((last, vec![init])),
+        }
+    }
+    let mut terms = Vec::new();
+    for (last, inits) in groups {
+        let init = match inits.len() {
+            1 => inits.into_iter().next().unwrap(),
+            _ => {
+                let mut alts = Vec::new();
+                inits.into_iter().for_each(|t| distinct_alternatives(t, &mut alts));
+                or(alts)
+            }
+        };
+        distinct_alternatives(concat(init, last), &mut terms);
+    }
+    // ε | r = r?
+    let has_eps = terms.contains(&Eps);
+    terms.retain(|r| *r != Eps);
+    match join(terms, Or) {
+        None if has_eps => Eps,
+        None => Empty,
+        Some(r) if has_eps => question(r),
+        Some(r) => r,
+    }
+}
+
+fn and(left: KyomuRegex, right: KyomuRegex) -> KyomuRegex {
+    let mut terms = Vec::new();
+    distinct_conjuncts(left, &mut terms);
+    distinct_conjuncts(right, &mut terms);
+    // ∅ & r = ∅, r & ~r = ∅
+    let contradiction = terms
+        .iter()
+        .any(|r| matches!(r, Not(r) if terms.contains(r)));
+    if contradiction || terms.contains(&Empty) {
+        return Empty;
+    }
+    // joined to the left like --, so r & ~s & ~t prints as r--s--t
+    let chain = terms
+        .into_iter()
+        .reduce(|acc, r| And(Box::new(acc), Box::new(r)));
+    chain.unwrap_or_else(|| Not(Box::new(Empty)))
+}
+
+fn star(r: KyomuRegex) -> KyomuRegex {
+    match r {
+        // ∅* = ε* = ε
+        Empty | Eps => Eps,
+        // (r*)* = (r+)* = (r?)* = r*
+        Star(r) | Plus(r) | Question(r) => Star(r),
+        r => Star(Box::new(r)),
+    }
+}
+
+fn plus(r: KyomuRegex) -> KyomuRegex {
+    match r {
+        Empty => Empty,
+        Eps => Eps,
+        // (r+)+ = r+
+        Plus(r) => Plus(r),
+        // (r*)+ = (r?)+ = r*
+        Star(r) | Question(r) => Star(r),
+        // r+ = r* when ε ∈ r
+        r if nullable(&r) => Star(Box::new(r)),
+        r => Plus(Box::new(r)),
+    }
+}
+
+fn question(r: KyomuRegex) -> KyomuRegex {
+    match r {
+        Empty | Eps => Eps,
+        // (r+)? = r*
+        Plus(r) => Star(r),
+        // r? = r when ε ∈ r
+        r if nullable(&r) => r,
+        r => Question(Box::new(r)),
+    }
+}
+
+fn repeat(min: u32, max: Option<u32>, r: KyomuRegex) -> KyomuRegex {
+    // r{4,2} = ∅, before counting anything
+    if max.is_some_and(|max| min > max) {
+        return Empty;
+    }
+    // iterations matching ε need not be counted
+    let min = if nullable(&r) { 0 } else { min };
+    match (min, max) {
+        (_, Some(0)) => Eps,
+        (1, Some(1)) => r,
+        (0, Some(1)) => question(r),
+        (0, None) => star(r),
+        (1, None) => plus(r),
+        _ => match r {
+            Eps => Eps,
+            Empty if min == 0 => Eps,
+            Empty => Empty,
+            // (r*){min,max} = r*
+            r @ Star(_) => r,
+            r => Repeat(min, max, Box::new(r)),
+        },
+    }
+}
+
+impl KyomuRegex {
+    // An equivalent regex, made smaller by the usual algebraic laws.
+    // Lazy quantifiers are kept as they are, since they affect `find`.
+    pub fn simplify(&self) -> KyomuRegex {
+        match self {
+            // [^] = (?s:.)
+            AnyExcept(chars) if chars.is_empty() => Any,
+            Char(_) | Any | AnyExcept(_) | Eps | Empty => self.clone(),
+            Concat(left, right) => concat(left.simplify(), right.simplify()),
+            Or(_, _) => {
+                // the whole chain at once, so common prefixes are factored only once
+                let mut chain = Vec::new();
+                distinct_alternatives(self.clone(), &mut chain);
+                let mut terms = Vec::new();
+                chain
+                    .iter()
+                    .for_each(|r| distinct_alternatives(r.simplify(), &mut terms));
+                or(terms)
+            }
+            Star(r) => star(r.simplify()),
+            Plus(r) => plus(r.simplify()),
+            Question(r) => question(r.simplify()),
+            Repeat(min, max, r) => repeat(*min, *max, r.simplify()),
+            And(left, right) => and(left.simplify(), right.simplify()),
+            Not(r) => match r.simplify() {
+                // ~~r = r
+                Not(r) => *r,
+                r => Not(Box::new(r)),
+            },
+            // only the body: r+? is not r*? even when r matches ε
+            Lazy(r) => Lazy(Box::new(match &**r {
+                Star(r) => Star(Box::new(r.simplify())),
+                Plus(r) => Plus(Box::new(r.simplify())),
+                Question(r) => Question(Box::new(r.simplify())),
+                Repeat(min, max, r) => Repeat(*min, *max, Box::new(r.simplify())),
+                r => r.simplify(),
+            })),
+            LookAhead(r) => LookAhead(Box::new(r.simplify())),
+            NegLookAhead(r) => NegLookAhead(Box::new(r.simplify())),
+            LookBehind(r, past) => LookBehind(Box::new(r.simplify()), Box::new(past.simplify())),
+            NegLookBehind(r, past) => {
+                NegLookBehind(Box::new(r.simplify()), Box::new(past.simplify()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lex::{Flags, Lexer};
+    use crate::parse::Parser;
+    use crate::KyomuRegex;
+    use crate::tests::strings_up_to;
+
+    // the regex as lowered, before simplification
+    fn lowered(pattern: &str) -> KyomuRegex {
+        let ast = Parser::new(Lexer::new(pattern)).unwrap().parse().unwrap();
+        KyomuRegex::build_from_ast(ast, Flags::default())
+    }
+
+    fn simplified(pattern: &str) -> String {
+        lowered(pattern).simplify().to_string()
+    }
+
+    #[test]
+    fn laws() {
+        assert_eq!(simplified("(a*)*"), "a*");
+        assert_eq!(simplified("(a|a)"), "a");
+        assert_eq!(simplified("(a?)*"), "a*");
+        assert_eq!(simplified("(()|a)+"), "a*");
+        assert_eq!(simplified("x{1}"), "x");
+        assert_eq!(simplified("x{0,}y{1,}z{0,1}"), "x*y+z?");
+        let invalid = KyomuRegex::Repeat(3, Some(2), Box::new(KyomuRegex::Char('w')));
+        assert_eq!(invalid.simplify(), KyomuRegex::Empty);
+        // even when the body matches ε
+        let invalid = KyomuRegex::Repeat(4, Some(2), Box::new(lowered("a?")));
+        assert_eq!(invalid.simplify(), KyomuRegex::Empty);
+        assert_eq!(simplified("ab|ac|a"), "a(b|c)?");
+        assert_eq!(simplified("abc|abd|e"), "ab(c|d)|e");
+        assert_eq!(simplified("ac|bc|c"), "(a|b)?c");
+        assert_eq!(simplified("aa*|b*bc"), "a+|b+c");
+        assert_eq!(simplified("a(--)|b()"), "b");
+        assert_eq!(simplified("(a*){2,5}"), "a*");
+        assert_eq!(simplified("(ab){2,5}"), "(ab){2,5}");
+        assert_eq!(simplified("a*?b"), "a*?b");
+        assert_eq!(simplified("a.b"), "a.b");
+    }
+
+    #[test]
+    fn optional_lookbehind() {
+        // the lookbehind fails after c, but ? lets it be skipped
+        for pattern in ["c(?<!c)?", "c(?<!c)*", "c((?<!c)|x)*"] {
+            let r = KyomuRegex::compile(pattern).unwrap();
+            assert!(r.whole_match("c"), "{} became {}", pattern, r);
+        }
+        // but not an iteration that is required
+        for pattern in ["c((?<!c)|x)+", "c((?<!c)|x){2,3}"] {
+            let r = KyomuRegex::compile(pattern).unwrap();
+            assert!(!r.whole_match("c"), "{} became {}", pattern, r);
+            assert!(r.whole_match("cxx"), "{} became {}", pattern, r);
+        }
+        assert_eq!(simplified("c(?<!c)?"), "c(?<!c)?");
+    }
+
+    #[test]
+    fn same_language() {
+        let patterns = [
+            "(a|ab)(c|bcd)(d*)",
+            "(a*b|ab*)*--(ab)+",
+            "((a|b)?){2,3}b{0,1}|ba",
+            "(?=ab)a*b|(?!b)(a|a)",
+            "a*(?<=ba)|(b?)+",
+            "(ab|aa|a)+?b",
+            "xb|yab|b|(ab)*ab",
+        ];
+        let inputs = strings_up_to(&['a', 'b', 'c', 'd'], 5);
+        for pattern in patterns {
+            let (before, after) = (lowered(pattern), lowered(pattern).simplify());
+            for input in &inputs {
+                assert_eq!(
+                    before.whole_match(input),
+                    after.whole_match(input),
+                    "{} on {:?}",
+                    pattern,
+                    input
+                );
+                assert_eq!(
+                    before.find(input),
+                    after.find(input),
+                    "{} in {:?}",
+                    pattern,
+                    input
+                );
+            }
+        }
+    }
+}